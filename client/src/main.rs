@@ -11,7 +11,25 @@ use std::io::Write;
 #[clap(setting = AppSettings::ColoredHelp)]
 struct Opts {
     input: String,
-    output: Option<String>
+    output: Option<String>,
+    /// Emit every word even if it is never called
+    #[clap(long)]
+    keep_all: bool,
+    /// Optimization level (0-3 or s to optimize for size)
+    #[clap(short = 'O', default_value = "1")]
+    olevel: String,
+    /// Enable an optimization pass
+    #[clap(long = "pass")]
+    pass: Vec<String>,
+    /// Disable an optimization pass
+    #[clap(long = "no-pass")]
+    no_pass: Vec<String>,
+    /// Print the AST after every optimization pass
+    #[clap(long)]
+    dump_ast: bool,
+    /// Check stack effects and print the maximum stack depth
+    #[clap(long)]
+    check_stack: bool
 }
 
 fn main() -> BoxResult<()> {
@@ -25,6 +43,27 @@ fn main() -> BoxResult<()> {
         }
     };
 
+    compiler.keep_all = opts.keep_all;
+    compiler.check_stack = opts.check_stack;
+    match opts.olevel.as_ref() {
+        "s" => compiler.optimizer.optimize_for_size(),
+        level => compiler.optimizer.level = match level.parse() {
+            Ok(level) => level,
+            Err(_) => return Err(format!("Invalid optimization level {}", level).into())
+        }
+    }
+    compiler.optimizer.dump = opts.dump_ast;
+    for pass in &opts.pass {
+        if !compiler.optimizer.enable(pass) {
+            return Err(format!("Unknown pass {}", pass).into());
+        }
+    }
+    for pass in &opts.no_pass {
+        if !compiler.optimizer.disable(pass) {
+            return Err(format!("Unknown pass {}", pass).into());
+        }
+    }
+
     let mut result = match compiler.compile() {
       Ok(result) => result,
       Err(err) => {
@@ -33,6 +72,21 @@ fn main() -> BoxResult<()> {
       }
     };
 
+    if !compiler.dedup.aliases.is_empty() {
+        for (word, target) in &compiler.dedup.aliases {
+            eprintln!("{} is an alias of {}", word, target);
+        }
+        eprintln!("Removed {} duplicate words, saved {} bytes",
+            compiler.dedup.aliases.len(), compiler.dedup.saved);
+    }
+
+    if opts.check_stack {
+        match compiler.max_depth() {
+            Some(depth) => eprintln!("Maximum stack depth: {}", depth),
+            None => eprintln!("Maximum stack depth: unknown")
+        }
+    }
+
     match opts.output {
         Some(s) => {
             let mut file = std::fs::File::create(s)?;
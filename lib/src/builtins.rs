@@ -4,6 +4,7 @@ use super::object::*;
 use super::callable::*;
 use super::interpreter::*;
 use super::token::*;
+use super::expr::*;
 
 /**
  * Interpreted builtins
@@ -231,3 +232,133 @@ impl Callable for NotEqual {
     }
 }
 
+
+#[derive(Clone)]
+pub struct Swap;
+
+impl Callable for Swap {
+    fn call(&mut self, interpreter: &mut Interpreter, token: &Token) -> BoxResult<Compiled> {
+        let y = interpreter.pop(token)?;
+        let x = interpreter.pop(token)?;
+
+        interpreter.push(y);
+        interpreter.push(x);
+
+        Ok(Compiled::new(vec![]))
+    }
+}
+
+/// joins any two values into a string
+#[derive(Clone)]
+pub struct Concat;
+
+impl Callable for Concat {
+    fn call(&mut self, interpreter: &mut Interpreter, token: &Token) -> BoxResult<Compiled> {
+        let y = interpreter.pop(token)?;
+        let x = interpreter.pop(token)?;
+
+        match (&x, &y) {
+            (Object::Callable(_), _) | (_, Object::Callable(_)) =>
+                return Err(Box::new(ExecError::new(ErrorType::TypeError, token.clone()))),
+            _ => interpreter.push(Object::Str(format!("{}{}", x.to_string(), y.to_string())))
+        };
+
+        Ok(Compiled::new(vec![]))
+    }
+}
+
+/// emitted statements point at the word that emitted them
+fn emitted_token(token: &Token, token_type: TokenType, literal: Object) -> Token {
+    let mut emitted = token.clone();
+    emitted.token_type = token_type;
+    emitted.lexeme = literal.to_string();
+    emitted.literal = literal;
+    emitted
+}
+
+fn literal_expr(token: &Token, value: Object) -> BoxResult<Expr> {
+    let token_type = match value {
+        Object::Number(_) => TokenType::Number,
+        Object::Real(_) => TokenType::Real,
+        Object::Str(_) => TokenType::Str,
+        _ => return Err(Box::new(ExecError::new(ErrorType::TypeError, token.clone())))
+    };
+    Ok(Expr::Literal(LiteralExpr::new(emitted_token(token, token_type, value))))
+}
+
+/// ( code -- ) emits an :asm statement
+#[derive(Clone)]
+pub struct EmitAsm;
+
+impl Callable for EmitAsm {
+    fn call(&mut self, interpreter: &mut Interpreter, token: &Token) -> BoxResult<Compiled> {
+        let code = interpreter.pop(token)?;
+
+        match code {
+            Object::Str(_) => {
+                let code_token = emitted_token(token, TokenType::Str, code.clone());
+                interpreter.emitted.push(Stmt::Asm(AsmStmt::new(code, code_token)));
+            },
+            _ => return Err(Box::new(ExecError::new(ErrorType::TypeError, token.clone())))
+        };
+
+        Ok(Compiled::new(vec![]))
+    }
+}
+
+/// ( name -- ) emits a call to a word
+#[derive(Clone)]
+pub struct EmitWord;
+
+impl Callable for EmitWord {
+    fn call(&mut self, interpreter: &mut Interpreter, token: &Token) -> BoxResult<Compiled> {
+        let name = interpreter.pop(token)?;
+
+        match name {
+            Object::Word(name) | Object::Str(name) => {
+                let word = emitted_token(token, TokenType::Word, Object::Word(name));
+                interpreter.emitted.push(Stmt::Expr(ExprStmt::new(Expr::Word(WordExpr::new(word)))));
+            },
+            _ => return Err(Box::new(ExecError::new(ErrorType::TypeError, token.clone())))
+        };
+
+        Ok(Compiled::new(vec![]))
+    }
+}
+
+/// ( value -- ) emits a literal
+#[derive(Clone)]
+pub struct EmitLiteral;
+
+impl Callable for EmitLiteral {
+    fn call(&mut self, interpreter: &mut Interpreter, token: &Token) -> BoxResult<Compiled> {
+        let value = interpreter.pop(token)?;
+        let literal = literal_expr(token, value)?;
+        interpreter.emitted.push(Stmt::Expr(ExprStmt::new(literal)));
+
+        Ok(Compiled::new(vec![]))
+    }
+}
+
+/// ( name value -- ) emits a :c definition
+#[derive(Clone)]
+pub struct EmitConst;
+
+impl Callable for EmitConst {
+    fn call(&mut self, interpreter: &mut Interpreter, token: &Token) -> BoxResult<Compiled> {
+        let value = interpreter.pop(token)?;
+        let name = interpreter.pop(token)?;
+
+        let name = match name {
+            Object::Word(name) | Object::Str(name) =>
+                emitted_token(token, TokenType::Word, Object::Word(name)),
+            _ => return Err(Box::new(ExecError::new(ErrorType::TypeError, token.clone())))
+        };
+        let literal = literal_expr(token, value)?;
+        let body = BlockStmt::new(vec![Stmt::Expr(ExprStmt::new(literal))], token.clone());
+        interpreter.emitted.push(Stmt::Define(
+                DefineStmt::new(name, Box::new(Stmt::Block(body)), DefineMode::Constant)));
+
+        Ok(Compiled::new(vec![]))
+    }
+}
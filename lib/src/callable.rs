@@ -6,6 +6,8 @@ use super::token::*;
 use std::fmt;
 use super::stmt::*;
 use super::stmt::Stmt;
+use super::effect::StackEffect;
+use super::object::Object;
 
 pub trait CallableClone {
     fn box_clone(&self) -> Box<dyn Callable>;
@@ -27,6 +29,21 @@ pub trait Callable: CallableClone {
     fn mode(&self) -> DefineMode {
         DefineMode::Regular
     }
+
+    /// the code that may be inlined in place of a call
+    fn inline_body(&self) -> Option<Compiled> {
+        None
+    }
+
+    /// the stack effect if it is known
+    fn effect(&self) -> Option<StackEffect> {
+        None
+    }
+
+    /// the width of a variable
+    fn width(&self) -> Option<String> {
+        None
+    }
 }
 
 impl<T> CallableClone for T where T: 'static + Callable + Clone {
@@ -63,6 +80,7 @@ pub struct StmtCallable {
 impl Callable for StmtCallable {
     fn call(&mut self, interpreter: &mut Interpreter, _token: &Token) -> BoxResult<Compiled> {
         interpreter.execute(&mut self.stmt)?;
+        interpreter.exiting = false;
         Ok(Compiled::new(vec![]))
     }
 
@@ -75,7 +93,11 @@ impl Callable for StmtCallable {
 #[derive(Clone)]
 pub struct CompiledCallable {
     pub compiled: Compiled,
-    pub mode: DefineMode
+    pub mode: DefineMode,
+    // body without compile and return
+    // None if the word may not be inlined
+    pub inline: Option<Compiled>,
+    pub effect: Option<StackEffect>
 }
 
 impl Callable for CompiledCallable {
@@ -90,4 +112,50 @@ impl Callable for CompiledCallable {
     fn mode(&self) -> DefineMode {
         self.mode
     }
+
+    fn inline_body(&self) -> Option<Compiled> {
+        self.inline.clone()
+    }
+
+    fn effect(&self) -> Option<StackEffect> {
+        self.effect
+    }
+}
+
+/// a variable declared with :let.
+/// using it pushes its address
+#[derive(Clone)]
+pub struct VarCallable {
+    pub width: String
+}
+
+impl Callable for VarCallable {
+    fn compile(&mut self, compiler: &mut Compiler, token: &Token) -> BoxResult<Compiled> {
+        compiler.call_word(token.clone(), "__tick", &Object::Word(token.lexeme.clone()))
+    }
+
+    fn mode(&self) -> DefineMode {
+        DefineMode::Inline
+    }
+
+    fn effect(&self) -> Option<StackEffect> {
+        Some(StackEffect::new(0, 1))
+    }
+
+    fn width(&self) -> Option<String> {
+        Some(self.width.clone())
+    }
+}
+
+/// a word that is called before its body is compiled.
+/// either forward declared or a word calling itself
+#[derive(Clone)]
+pub struct ForwardCallable {
+    pub effect: Option<StackEffect>
+}
+
+impl Callable for ForwardCallable {
+    fn effect(&self) -> Option<StackEffect> {
+        self.effect
+    }
 }
@@ -11,9 +11,22 @@ use std::str;
 use std::path::{Path, PathBuf};
 use super::filesystem::*;
 use std::rc::Rc;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use super::optimizer::Optimizer;
+use super::peephole::*;
+use super::treeshake::TreeShaker;
+use super::effect::*;
+use super::outline::*;
+use super::dedup::*;
+
+/// the result of compiling an imported file
+/// that is shared with every file that imports it
+#[derive(Clone)]
+pub struct CompiledModule {
+    pub dictionary: Box<Dictionary>,
+    pub peephole: Vec<PeepholeRule>
+}
 
 pub struct Compiler {
     stmts: Vec<Stmt>,
@@ -21,19 +34,66 @@ pub struct Compiler {
     pub dictionary: Box<Dictionary>,
 
     mod_name: Option<String>,
-    pub filesystem: Box<dyn FileSystemManager>,
+    // shared with the parser and imported modules
+    pub filesystem: Rc<dyn FileSystemManager>,
 
     // tracks which modules have already been compiled
     // skips compilation of such modules
-    pub module_tracker: Rc<RefCell<HashMap<String, Box<Dictionary>>>>,
+    pub module_tracker: Rc<RefCell<HashMap<String, CompiledModule>>>,
+
+    // rewrite rules applied to the output
+    pub peephole: Vec<PeepholeRule>,
+
+    // emit every word even if it is never called
+    pub keep_all: bool,
+
+    // output of imported modules that still needs to be
+    // added to the output
+    imported: Vec<Compiled>,
+
+    // how often each word is used in the whole program by its full name.
+    // shared with imported modules
+    uses: Rc<RefCell<HashMap<String, usize>>>,
+
+    // words defined by imported modules. never constant folded
+    imported_words: HashSet<String>,
+
+    // forward declared words that are not defined yet
+    forwards: HashMap<String, Token>,
+
+    // set once exit is compiled. words that exit early are not inlined
+    exits: bool,
+
+    // check the stack effect of every word and of the top-level code
+    pub check_stack: bool,
+    stack: StackState,
+
+    // compiled size of every statement inside of a block
+    sizes: StmtSizes,
+    // number of generated outline words shared with imported modules
+    outlines: Rc<Cell<usize>>,
+    // number of case statements shared with imported modules.
+    // keeps the labels of case statements unique
+    cases: Rc<Cell<usize>>,
+    // number of macro expansions shared with imported modules
+    expansions: Rc<Cell<usize>>,
+    // the macro expansion that is compiled.
+    // keeps the labels of its statements unique
+    expansion: Option<usize>,
+
+    // words that were replaced by an alias
+    pub dedup: DedupReport,
 
     parent_dir: PathBuf,
 
     halt: bool,
-    olevel: usize
+    pub optimizer: Optimizer
 }
 
 impl Compiler {
+    /// case statements with fewer branches always compare
+    const MIN_CASE_TABLE: usize = 3;
+
     pub fn builtins() -> Box<Dictionary> {
         let builtins = Box::new(Dictionary::new());
 
@@ -51,25 +111,78 @@ impl Compiler {
     }
 
     pub fn new(source: &str, path: &str) -> Result<Self, ErrorList> {
-        let mut parser = Parser::new(source, path)?;
+        Self::with_filesystem(source, path, Rc::new(LocalFileSystem))
+    }
+
+    /// imported files are read from the filesystem while parsing already
+    pub fn with_filesystem(source: &str, path: &str, filesystem: Rc<dyn FileSystemManager>) -> Result<Self, ErrorList> {
+        let mut parser = Parser::with_filesystem(source, path, filesystem.clone())?;
         let stmts = parser.parse()?;
-        Ok(Self {
+        let mut compiler = Self::with(stmts, path);
+        compiler.filesystem = filesystem;
+        Ok(compiler)
+    }
+
+    pub fn with(stmts: Vec<Stmt>, path: &str) -> Self {
+        let mut imported_words = HashSet::new();
+        for module in Self::imported_modules(&stmts).values() {
+            Optimizer::collect_definitions(&module.stmts, &mut imported_words);
+        }
+
+        Self {
             stmts,
             dictionary: Self::builtins(),
             mod_name: None,
-            filesystem: Box::new(LocalFileSystem),
+            filesystem: Rc::new(LocalFileSystem),
             module_tracker: Rc::new(RefCell::new(HashMap::new())),
+            peephole: vec![],
+            keep_all: false,
+            imported: vec![],
+            uses: Rc::new(RefCell::new(HashMap::new())),
+            imported_words,
+            forwards: HashMap::new(),
+            exits: false,
+            check_stack: false,
+            stack: StackState::new(0, true),
+            sizes: HashMap::new(),
+            outlines: Rc::new(Cell::new(0)),
+            cases: Rc::new(Cell::new(0)),
+            expansions: Rc::new(Cell::new(0)),
+            expansion: None,
+            dedup: DedupReport::default(),
             parent_dir: Path::new(path).parent().unwrap_or(Path::new(path)).to_path_buf(),
             halt: false,
-            olevel: 1
-        })
+            optimizer: Optimizer::new(1)
+        }
     }
 
     pub fn compile(&mut self) -> Result<Vec<Compiled>, ErrorList> {
-        for _ in [0..self.olevel] {
-            Optimizer::optimize(&mut self.stmts);
+        for module in Self::imported_modules(&self.stmts).values() {
+            Self::count_file_uses(&module.stmts, &mut self.uses.borrow_mut());
+        }
+        Self::count_file_uses(&self.stmts, &mut self.uses.borrow_mut());
+        let mut output = self.compile_unit()?;
+
+        if !self.keep_all {
+            TreeShaker::shake(&mut output);
+        }
+        if let Err(err) = self.deduplicate(&mut output) {
+            return Err(ErrorList::new(vec![err]));
+        }
+        if self.optimizer.is_enabled("peephole") {
+            Peephole::apply(&self.peephole, &mut output);
         }
 
+        return Ok(output);
+    }
+
+    /// compiles this file without any whole-program passes.
+    /// every word definition is its own fragment in the output
+    fn compile_unit(&mut self) -> Result<Vec<Compiled>, ErrorList> {
+        self.optimizer.imported_words = self.imported_words.clone();
+        self.optimizer.optimize(&mut self.stmts);
+        self.outline();
+
         let mut output = vec![];
         let mut errors = vec![];
 
@@ -82,10 +195,41 @@ impl Compiler {
         };
         self.filesystem.set_current_dir(self.parent_dir.to_str().unwrap_or(""));
 
-        for mut stmt in self.stmts.clone() {
-            match self.execute(&mut stmt) {
+        let mut stmts = self.stmts.clone();
+        let mut i = 0;
+        while i < stmts.len() {
+            // conditions may use every constant defined before them
+            if let Stmt::Cond(cond) = &stmts[i] {
+                match self.taken_branch(cond) {
+                    Ok(branch) => {
+                        stmts.splice(i..i+1, branch);
+                        continue;
+                    },
+                    Err(err) => {
+                        errors.push(err);
+                        break;
+                    }
+                }
+            }
+            if let Err(err) = self.resolve_in(&mut stmts[i]) {
+                errors.push(err);
+                break;
+            }
+
+            let (result, count) = match self.var_access(&stmts, i) {
+                Ok(Some(bytes)) => (Ok(bytes), 2),
+                Ok(None) => (self.execute(&mut stmts[i].clone()), 1),
+                Err(err) => (Err(err), 1)
+            };
+
+            match result {
                 Ok(bytes) => {
+                    output.append(&mut self.imported);
                     output.push(bytes);
+                    if let Err(err) = stmts[i..i+count].iter().try_for_each(|stmt| self.check_top_level(stmt)) {
+                        errors.push(err);
+                        break;
+                    }
                 },
                 Err(err) => {
                     errors.push(err);
@@ -96,10 +240,19 @@ impl Compiler {
             if self.halt {
                 break;
             }
+            i += count;
         }
 
         self.filesystem.set_current_dir(previous_dir.to_str().unwrap_or(""));
 
+        if errors.is_empty() {
+            let mut forwards: Vec<&Token> = self.forwards.values().collect();
+            forwards.sort_by_key(|token| (token.line, token.start));
+            for token in forwards {
+                errors.push(Box::new(ExecError::new(ErrorType::UndefinedForward, token.clone())));
+            }
+        }
+
         if errors.len() > 0 {
             return Err(ErrorList::new(errors));
         }
@@ -107,6 +260,127 @@ impl Compiler {
         return Ok(output);
     }
 
+    /// the statements of the branch a conditional compilation takes
+    fn taken_branch(&self, stmt: &CondStmt) -> BoxResult<Vec<Stmt>> {
+        let taken = match &stmt.condition {
+            Condition::Defined(name) => self.dictionary.get_any(name, self.build_imports(&name.lexeme)).is_ok(),
+            Condition::Expr(condition) => {
+                let mut interpreter = Interpreter::with_constants(vec![*condition.clone()],
+                    &self.dictionary, &self.mod_name);
+                interpreter.interprete()?;
+                interpreter.pop(&stmt.token)?.truthy()
+            }
+        };
+
+        if taken {
+            Ok(stmt.then_branch.clone())
+        } else {
+            Ok(stmt.else_branch.clone())
+        }
+    }
+
+    /// replaces every conditional compilation inside of blocks
+    /// by the taken branch. skipped branches are never compiled
+    fn resolve(&self, stmts: &mut Vec<Stmt>) -> BoxResult<()> {
+        let mut i = 0;
+        while i < stmts.len() {
+            if let Stmt::Cond(cond) = &stmts[i] {
+                let branch = self.taken_branch(cond)?;
+                stmts.splice(i..i+1, branch);
+                continue;
+            }
+            self.resolve_in(&mut stmts[i])?;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    fn resolve_in(&self, stmt: &mut Stmt) -> BoxResult<()> {
+        match stmt {
+            Stmt::Block(block) => self.resolve(&mut block.body)?,
+            Stmt::Define(define) => self.resolve_in(&mut define.body)?,
+            Stmt::If(if_stmt) => {
+                self.resolve_in(&mut if_stmt.then_block)?;
+                if let Some(else_block) = &mut if_stmt.else_block {
+                    self.resolve_in(else_block)?;
+                }
+            },
+            Stmt::Loop(loop_stmt) => self.resolve_in(&mut loop_stmt.block)?,
+            Stmt::While(while_stmt) => {
+                self.resolve_in(&mut while_stmt.condition)?;
+                self.resolve_in(&mut while_stmt.block)?;
+            },
+            Stmt::Case(case_stmt) => {
+                for branch in &mut case_stmt.branches {
+                    self.resolve_in(&mut branch.value)?;
+                    self.resolve_in(&mut branch.block)?;
+                }
+                self.resolve_in(&mut case_stmt.default)?;
+            },
+            Stmt::Do(do_stmt) => self.resolve_in(&mut do_stmt.block)?,
+            Stmt::Expand(expand) => self.resolve(&mut expand.body)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// the highest data stack depth the top-level code can reach.
+    /// None if it is unknown or the stack is not checked
+    pub fn max_depth(&self) -> Option<usize> {
+        if !self.check_stack {
+            return None;
+        }
+        self.stack.effect().map(|effect| effect.peak)
+    }
+
+    fn lookup_effect(&self, token: &Token) -> Option<StackEffect> {
+        match self.dictionary.get_any(token, self.build_imports(&token.lexeme)) {
+            Ok(Object::Callable(c)) => c.effect(),
+            Ok(_) => Some(StackEffect::new(0, 1)),
+            Err(_) => None
+        }
+    }
+
+    fn check_top_level(&mut self, stmt: &Stmt) -> BoxResult<()> {
+        if !self.check_stack {
+            return Ok(());
+        }
+
+        let mut stack = self.stack;
+        stack.check(stmt, &|token: &Token| self.lookup_effect(token))?;
+        self.stack = stack;
+        Ok(())
+    }
+
+    /// computes the stack effect of a word from its body.
+    /// a declared effect is checked against the body instead.
+    /// words made of raw code only have a known effect if it is declared
+    fn word_effect(&self, stmt: &DefineStmt) -> BoxResult<Option<StackEffect>> {
+        if !self.check_stack {
+            return Ok(stmt.effect);
+        }
+
+        let lookup = |token: &Token| self.lookup_effect(token);
+        match stmt.effect {
+            Some(declared) => {
+                let mut state = StackState::new(declared.inputs, true);
+                state.check(&stmt.body, &lookup)?;
+                if !state.known {
+                    return Ok(Some(declared));
+                }
+                if state.depth != declared.outputs as isize {
+                    return Err(Box::new(ExecError::new(ErrorType::StackEffectMismatch, stmt.token())));
+                }
+                Ok(Some(StackEffect::with(declared.inputs, declared.outputs, state.peak as usize)))
+            },
+            None => {
+                let mut state = StackState::new(0, false);
+                state.check(&stmt.body, &lookup)?;
+                Ok(state.effect())
+            }
+        }
+    }
+
     fn execute(&mut self, stmt: &mut Stmt) -> BoxResult<Compiled> {
         stmt.accept(self)
     }
@@ -115,6 +389,30 @@ impl Compiler {
         expr.accept(self)
     }
 
+    /// emits words with identical bodies only once
+    /// if the stdlib defines alias
+    fn deduplicate(&mut self, output: &mut [Compiled]) -> BoxResult<()> {
+        let alias = Token::new(TokenType::Word, Object::Nil, "alias", 0, 0, "");
+        if !self.optimizer.is_enabled("dedup")
+            || self.dictionary.get_any(&alias, self.build_imports("alias")).is_err() {
+            return Ok(());
+        }
+
+        self.dedup = Deduplicator::dedup(output, &mut |word, target| {
+            let mut token = alias.clone();
+            token.lexeme = word.into();
+            self.call_word_with(token, "alias", &Object::Nil, &[
+                ("__ARG__", Dictionary::get_label(word)),
+                ("__TARGET__", Dictionary::get_label(target))
+            ])
+        })?;
+        Ok(())
+    }
+
+    pub fn call_word(&mut self, token: Token, name: &str, object: &Object) -> BoxResult<Compiled> {
+        self.call_word_with(token, name, object, &[])
+    }
+
     /// calls an external word (usually platform specific asm block)
     /// and replaces certain pre-defined constants with strings
     /// constants: __ARG__ == current object in question; __WORD__ == current word;
@@ -122,7 +420,9 @@ impl Compiler {
     /// it is very basic text-replacement similar to C #defines
     /// be aware that an asm block can be anything and the compiler does not
     /// know how to assemble it.
-    fn call_word(&mut self, mut token: Token, name: &str, object: &Object) -> BoxResult<Compiled> {
+    /// vars are replaced before the constants
+    fn call_word_with(&mut self, mut token: Token, name: &str, object: &Object,
+        vars: &[(&str, String)]) -> BoxResult<Compiled> {
         token.lexeme = name.into();
         let mut call_obj = self.dictionary.get_any(&token, self.build_imports(&token.lexeme))?;
         let compiled = match &mut call_obj {
@@ -134,16 +434,21 @@ impl Compiler {
 
         // apply constants
         let mut cstr = str::from_utf8(&compiled.data)?.to_string();
+        for (name, value) in vars {
+            cstr = cstr.replace(name, value);
+        }
+        let mut refs = compiled.refs;
         match object {
             Object::Callable(_) | Object::Word(_) => {
                 let mut tmptoken = token.clone();
                 tmptoken.lexeme = object.to_string();
 
-                cstr = cstr.replace("__ARG__",
-                &self.dictionary.resolve_full_name(&tmptoken,
+                let full_name = self.dictionary.resolve_full_name(&tmptoken,
                     self.build_imports(&object.to_string()),
-                    &self.mod_name)
-                .replace("::", "__mod__"))
+                    &self.mod_name);
+                cstr = cstr.replace("__ARG__", &Dictionary::get_label(&full_name));
+                // the word is referenced by this code
+                refs.push(full_name);
             },
             _ => cstr = cstr.replace("__ARG__", &object.to_string())
         }
@@ -152,9 +457,377 @@ impl Compiler {
                 self.build_imports(&token.lexeme),
                 &self.mod_name))
             .replace("::", "__");
-        cstr = cstr.replace("__LINE__", &token.line.to_string());
+        let line = match self.expansion {
+            Some(id) => format!("{}_{}", token.line, id),
+            None => token.line.to_string()
+        };
+        cstr = cstr.replace("__LINE__", &line);
+
+        let mut result = Compiled::new(cstr.into_bytes());
+        result.refs = refs;
+        Ok(result)
+    }
+
+    /// factors repeated sequences out of word bodies
+    /// as long as this makes the output smaller.
+    /// the size of each statement is measured by compiling the file
+    /// once without outlining
+    fn outline(&mut self) {
+        if !self.optimizer.is_enabled("outline") {
+            return;
+        }
+
+        let mut probe = Compiler::with(self.stmts.clone(), "");
+        probe.parent_dir = self.parent_dir.clone();
+        probe.filesystem = self.filesystem.clone();
+        // modules compiled by the probe are not part of the output.
+        // the real compilation has to compile them again
+        probe.module_tracker = Rc::new(RefCell::new(self.module_tracker.borrow().clone()));
+        probe.optimizer = self.optimizer.clone();
+        probe.optimizer.disable("outline");
+        probe.keep_all = self.keep_all;
+        probe.uses = self.uses.clone();
+        if probe.compile_unit().is_err() {
+            // errors are reported by the real compilation
+            return;
+        }
+
+        loop {
+            let name = format!("{}{}", Outliner::PREFIX, self.outlines.get());
+            let (call, overhead) = match probe.outline_cost(&name) {
+                Ok(cost) => cost,
+                _ => return
+            };
+
+            let best = Outliner::find(&self.stmts, &probe.sizes).into_iter()
+                .map(|sequence| (sequence.savings(call, overhead), sequence))
+                .filter(|(savings, _)| *savings > 0)
+                .max_by_key(|(savings, _)| *savings);
+
+            match best {
+                Some((_, sequence)) => {
+                    Outliner::replace(&mut self.stmts, &sequence, &name);
+                    self.outlines.set(self.outlines.get() + 1);
+                },
+                None => return
+            }
+        }
+    }
+
+    /// the size of a call to a word and
+    /// the size of compile and return of the word
+    fn outline_cost(&mut self, name: &str) -> BoxResult<(usize, usize)> {
+        let token = Token::new(TokenType::Word, Object::Word(name.into()), name, 0, 0, "");
+        let word = Object::Word(name.into());
+
+        let call = self.call_word(token.clone(), "call", &word)?.data.len();
+        let prefix = self.call_word(token.clone(), "compile", &word)?.data.len();
+        let postfix = self.call_word(token, "return", &word)?.data.len();
+        Ok((call, prefix + postfix))
+    }
+
+    /// every file imported by the statements or by the files they import
+    /// by its source. imports in conditional compilations are included
+    fn imported_modules(stmts: &[Stmt]) -> HashMap<String, Rc<ParsedModule>> {
+        let mut modules = HashMap::new();
+        Self::collect_modules(stmts, &mut modules);
+        modules
+    }
+
+    fn collect_modules(stmts: &[Stmt], modules: &mut HashMap<String, Rc<ParsedModule>>) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Import(import) => {
+                    if let Some(module) = &import.module {
+                        if !modules.contains_key(&module.source) {
+                            modules.insert(module.source.clone(), module.clone());
+                            Self::collect_modules(&module.stmts, modules);
+                        }
+                    }
+                },
+                Stmt::Block(block) => Self::collect_modules(&block.body, modules),
+                Stmt::Cond(cond) => {
+                    Self::collect_modules(&cond.then_branch, modules);
+                    Self::collect_modules(&cond.else_branch, modules);
+                },
+                Stmt::Expand(expand) => Self::collect_modules(&expand.body, modules),
+                _ => {}
+            }
+        }
+    }
+
+    /// counts how often every word of a file is used by its full name
+    fn count_file_uses(stmts: &[Stmt], uses: &mut HashMap<String, usize>) {
+        let mut names = HashMap::new();
+        Self::full_names(stmts, &mut None, &mut names);
+        Self::count_uses(stmts, &names, uses);
+    }
+
+    /// the full name of every word a file defines or brings into scope
+    /// with :use. all other words are global
+    fn full_names(stmts: &[Stmt], mod_name: &mut Option<String>, names: &mut HashMap<String, String>) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Mod(mod_stmt) => *mod_name = Some(mod_stmt.name.lexeme.clone()),
+                Stmt::Define(define) => {
+                    names.entry(define.name.lexeme.clone())
+                        .or_insert_with(|| Dictionary::get_full_name(&define.name.lexeme, mod_name));
+                },
+                Stmt::Use(use_stmt) => {
+                    // aliases are looked up before any definition
+                    let module = Some(use_stmt.module.lexeme.clone());
+                    for word in &use_stmt.words {
+                        names.insert(word.lexeme.clone(), Dictionary::get_full_name(&word.lexeme, &module));
+                    }
+                },
+                Stmt::Block(block) => Self::full_names(&block.body, mod_name, names),
+                Stmt::Cond(cond) => {
+                    Self::full_names(&cond.then_branch, mod_name, names);
+                    Self::full_names(&cond.else_branch, mod_name, names);
+                },
+                Stmt::Expand(expand) => Self::full_names(&expand.body, mod_name, names),
+                _ => {}
+            }
+        }
+    }
+
+    fn count_uses(stmts: &[Stmt], names: &HashMap<String, String>, uses: &mut HashMap<String, usize>) {
+        for stmt in stmts {
+            let expr = match stmt {
+                Stmt::Expr(expr) => &expr.expr,
+                Stmt::Tick(tick) => &tick.word,
+                Stmt::Block(block) => {
+                    Self::count_uses(&block.body, names, uses);
+                    continue;
+                },
+                Stmt::Define(define) => {
+                    Self::count_uses(std::slice::from_ref(&define.body), names, uses);
+                    continue;
+                },
+                Stmt::If(if_stmt) => {
+                    Self::count_uses(std::slice::from_ref(&if_stmt.then_block), names, uses);
+                    if let Some(else_block) = &if_stmt.else_block {
+                        Self::count_uses(std::slice::from_ref(else_block), names, uses);
+                    }
+                    continue;
+                },
+                Stmt::Loop(loop_stmt) => {
+                    Self::count_uses(std::slice::from_ref(&loop_stmt.block), names, uses);
+                    continue;
+                },
+                Stmt::While(while_stmt) => {
+                    Self::count_uses(std::slice::from_ref(&while_stmt.condition), names, uses);
+                    Self::count_uses(std::slice::from_ref(&while_stmt.block), names, uses);
+                    continue;
+                },
+                Stmt::Case(case_stmt) => {
+                    for branch in &case_stmt.branches {
+                        Self::count_uses(std::slice::from_ref(&branch.value), names, uses);
+                        Self::count_uses(std::slice::from_ref(&branch.block), names, uses);
+                    }
+                    Self::count_uses(std::slice::from_ref(&case_stmt.default), names, uses);
+                    continue;
+                },
+                Stmt::Do(do_stmt) => {
+                    Self::count_uses(std::slice::from_ref(&do_stmt.block), names, uses);
+                    continue;
+                },
+                Stmt::Cond(cond) => {
+                    // the taken branch is not known yet
+                    Self::count_uses(&cond.then_branch, names, uses);
+                    Self::count_uses(&cond.else_branch, names, uses);
+                    continue;
+                },
+                Stmt::Expand(expand) => {
+                    Self::count_uses(&expand.body, names, uses);
+                    continue;
+                },
+                _ => continue
+            };
+
+            if let Expr::Word(word) = expr {
+                let name = names.get(&word.name.lexeme).unwrap_or(&word.name.lexeme);
+                *uses.entry(name.clone()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// regular words are inlined if their body is smaller than
+    /// the call and return overhead or if they are only used once.
+    /// a word that is only used once is removed afterwards
+    fn auto_inline(&mut self, body: Option<Compiled>, token: &Token, call: &Compiled) -> BoxResult<Option<Compiled>> {
+        let body = match body {
+            Some(body) if self.optimizer.is_enabled("inline") => body,
+            _ => return Ok(None)
+        };
+
+        let postfix = self.call_word(token.clone(), "return", &Object::Word(token.lexeme.clone()))?;
+        let full_name = self.dictionary.resolve_full_name(token,
+            self.build_imports(&token.lexeme), &self.mod_name);
+        let once = !self.keep_all
+            && self.optimizer.is_enabled("inline-once")
+            && self.uses.borrow().get(&full_name) == Some(&1);
+        if body.data.len() < call.data.len() + postfix.data.len() || once {
+            Ok(Some(body))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// if the last statement of a word's body calls another regular word
+    /// and the stdlib defines tailcall the statement is removed from the body.
+    /// returns the called word and the code a regular call would emit
+    fn tail_call(&mut self, body: &mut Stmt) -> BoxResult<Option<(Token, Compiled)>> {
+        let tailcall = Token::new(TokenType::Word, Object::Nil, "tailcall", 0, 0, "");
+        if !self.optimizer.is_enabled("tailcall")
+            || self.dictionary.get_any(&tailcall, self.build_imports("tailcall")).is_err() {
+            return Ok(None);
+        }
+
+        let block = match body {
+            Stmt::Block(block) => block,
+            _ => return Ok(None)
+        };
+        let token = match block.body.last() {
+            Some(Stmt::Expr(ExprStmt { expr: Expr::Word(word) })) => word.name.clone(),
+            _ => return Ok(None)
+        };
+
+        let inline_body = match self.dictionary.get_any(&token, self.build_imports(&token.lexeme)) {
+            Ok(Object::Callable(c)) if c.mode() == DefineMode::Regular => c.inline_body(),
+            _ => return Ok(None)
+        };
+
+        // inlining the word is better than jumping to it
+        let call = self.call_word(token.clone(), "call", &Object::Word(token.lexeme.clone()))?;
+        if self.auto_inline(inline_body, &token, &call)?.is_some() {
+            return Ok(None);
+        }
+
+        // name ! of a variable is a single store
+        if block.body.len() >= 2 && self.var_width(&block.body, block.body.len() - 2).is_some() {
+            return Ok(None);
+        }
+
+        block.body.pop();
+        Ok(Some((token, call)))
+    }
+
+    /// compiles statements in order and
+    /// records the size of each of them
+    fn compile_body(&mut self, stmts: &mut [Stmt]) -> BoxResult<Compiled> {
+        let mut compiled = Compiled::new(vec![]);
+
+        let mut i = 0;
+        while i < stmts.len() {
+            let (mut bytes, count) = match self.var_access(stmts, i)? {
+                Some(bytes) => (bytes, 2),
+                None => (self.execute(&mut stmts[i])?, 1)
+            };
+            self.record_size(&stmts[i], StmtSize::Bytes(bytes.data.len()));
+            if count == 2 {
+                // the outliner may not separate the variable from its access
+                self.record_size(&stmts[i + 1], StmtSize::Joined);
+            }
+            compiled.append(&mut bytes);
+            i += count;
+        }
+
+        Ok(compiled)
+    }
+
+    /// statements that share their position with another statement
+    /// have no known size
+    fn record_size(&mut self, stmt: &Stmt, size: StmtSize) {
+        let token = stmt.token();
+        self.sizes.entry((token.line, token.start))
+            .and_modify(|known| *known = StmtSize::Unknown)
+            .or_insert(size);
+    }
+
+    /// the variable, fetch or store word and width if stmts[i] is a variable
+    /// directly followed by @ or !
+    fn var_width(&self, stmts: &[Stmt], i: usize) -> Option<(Token, &'static str, String)> {
+        let (name, op) = match (stmts.get(i), stmts.get(i + 1)) {
+            (Some(Stmt::Expr(ExprStmt { expr: Expr::Word(name) })),
+                Some(Stmt::Expr(ExprStmt { expr: Expr::Word(op) }))) => (&name.name, &op.name),
+            _ => return None
+        };
+        let access = match op.lexeme.as_ref() {
+            "@" => "__fetch",
+            "!" => "__store",
+            _ => return None
+        };
+
+        match self.dictionary.get_any(name, self.build_imports(&name.lexeme)) {
+            Ok(Object::Callable(c)) => Some((name.clone(), access, c.width()?)),
+            _ => None
+        }
+    }
+
+    /// name @ and name ! call the fetch and store word of the variable's width.
+    /// None if stmts[i] does not access a variable
+    fn var_access(&mut self, stmts: &[Stmt], i: usize) -> BoxResult<Option<Compiled>> {
+        match self.var_width(stmts, i) {
+            Some((name, access, width)) => {
+                let word = format!("{}_{}", access, width);
+                let arg = Object::Word(name.lexeme.clone());
+                Ok(Some(self.call_word(name, &word, &arg)?))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// expands an inline template word without any arguments.
+    /// templates using __ARG__ or __LINE__ expand differently at every call
+    /// so a rule could never match them
+    fn expand_template(&mut self, token: &Token) -> BoxResult<Vec<u8>> {
+        match self.dictionary.get_any(token, self.build_imports(&token.lexeme))? {
+            Object::Callable(mut c) if c.mode() == DefineMode::Inline => {
+                let raw = c.compile(self, token)?.data;
+                let raw = String::from_utf8_lossy(&raw);
+                if raw.contains("__ARG__") || raw.contains("__LINE__") {
+                    return Err(Box::new(ExecError::new(ErrorType::InvalidPeephole, token.clone())));
+                }
+                Ok(self.call_word(token.clone(), &token.lexeme, &Object::Nil)?.data)
+            },
+            _ => Err(Box::new(ExecError::new(ErrorType::UnsupportedObject, token.clone())))
+        }
+    }
+
+    /// the values of a case statement if the stdlib defines __casetable
+    /// and every value is a constant in a range that is dense enough for a jump table
+    fn case_values(&mut self, stmt: &CaseStmt) -> Option<Vec<ObjNumber>> {
+        let table = Token::new(TokenType::Word, Object::Nil, "__casetable", 0, 0, "");
+        if stmt.branches.len() < Self::MIN_CASE_TABLE
+            || self.dictionary.get_any(&table, self.build_imports("__casetable")).is_err() {
+            return None;
+        }
 
-        Ok(Compiled::new(cstr.into_bytes()))
+        let mut values = vec![];
+        for branch in &stmt.branches {
+            let value = match &*branch.value {
+                Stmt::Block(block) if block.body.len() == 1 => match &block.body[0] {
+                    Stmt::Expr(ExprStmt { expr: Expr::Literal(literal) }) => literal.literal.literal.clone(),
+                    Stmt::Expr(ExprStmt { expr: Expr::Word(word) }) => {
+                        self.dictionary.get_any(&word.name, self.build_imports(&word.name.lexeme)).ok()?
+                    },
+                    _ => return None
+                },
+                _ => return None
+            };
+            match value {
+                Object::Number(n) => values.push(n),
+                _ => return None
+            }
+        }
+
+        let min = *values.iter().min()?;
+        let max = *values.iter().max()?;
+        if max.checked_sub(min)? >= values.len() as ObjNumber * 2 {
+            return None;
+        }
+        Some(values)
     }
 
     /// creates an imported module list based on the requested token
@@ -176,8 +849,12 @@ impl StmtVisitor for Compiler {
                     },
                     DefineMode::Regular => {
                         // arg should be the called word
-                        // TODO we need the fully qualified name here
-                        return self.call_word(stmt.token(), "call", &Object::Word(stmt.token().lexeme.clone()));
+                        let token = stmt.token();
+                        let call = self.call_word(token.clone(), "call", &Object::Word(token.lexeme.clone()))?;
+                        if let Some(body) = self.auto_inline(c.inline_body(), &token, &call)? {
+                            return Ok(body);
+                        }
+                        return Ok(call);
                     },
                     DefineMode::Constant => {
                         // this cant happen for callables
@@ -213,48 +890,80 @@ impl StmtVisitor for Compiler {
         let scope = Box::new(Dictionary::new());
         let prev = std::mem::replace(&mut self.dictionary, scope);
         self.dictionary.parent = Some(prev);
-        let mut compiled = Compiled::new(vec![]);
-
-        for stmt in &mut stmt.body {
-            compiled.data.append(&mut self.execute(stmt)?.data);
-        }
+        let compiled = self.compile_body(&mut stmt.body);
 
         // move env back
         let no_parent = None;
         let parent = std::mem::replace(&mut self.dictionary.parent, no_parent);
         let _ = std::mem::replace(&mut self.dictionary, parent.unwrap());
 
-        Ok(compiled)
+        compiled
     }
 
     fn visit_define(&mut self, stmt: &mut DefineStmt) -> BoxResult<Compiled> {
+        let full_name = Dictionary::get_full_name(&stmt.name.lexeme, &self.mod_name);
+        if self.forwards.remove(&full_name).is_some() && stmt.mode != DefineMode::Regular {
+            return Err(Box::new(ExecError::new(ErrorType::InvalidForward, stmt.name.clone())));
+        }
+
         match stmt.mode {
             DefineMode::Regular => {
-                let mut compiled_exec = self.execute(&mut stmt.body)?;
+                // the body may call the word itself
+                self.dictionary.define(&stmt.name.lexeme,
+                    &self.mod_name,
+                    &Object::Callable(Box::new(ForwardCallable { effect: stmt.effect })));
+                let effect = self.word_effect(stmt)?;
+                let mut body = stmt.body.clone();
+                let tail = self.tail_call(&mut body)?;
+                self.exits = false;
+                let mut compiled_exec = self.execute(&mut body)?;
+                let inline = if stmt.has_attribute(TokenType::NoInline) || self.exits {
+                    None
+                } else {
+                    // an inlined body has to call the tail instead of jumping to it
+                    let mut inline = compiled_exec.clone();
+                    if let Some((_, call)) = &tail {
+                        inline.append(&mut call.clone());
+                    }
+                    Some(inline)
+                };
 
                 // call compile word
                 let token = stmt.token();
                 let mut prefix = self.call_word(token.clone(), "compile", &Object::Word(stmt.name.lexeme.clone()))?;
-                let mut postfix = self.call_word(token, "return", &Object::Word(stmt.name.lexeme.clone()))?;
-                prefix.data.append(&mut compiled_exec.data);
-                prefix.data.append(&mut postfix.data);
-                let compiled = Compiled::new(prefix.data);
+                let mut postfix = match tail {
+                    Some((tail_token, _)) => self.call_word(tail_token.clone(), "tailcall",
+                        &Object::Word(tail_token.lexeme.clone()))?,
+                    None => self.call_word(token, "return", &Object::Word(stmt.name.lexeme.clone()))?
+                };
+                let label = prefix.data.len();
+                prefix.append(&mut compiled_exec);
+                prefix.append(&mut postfix);
+                let mut compiled = prefix;
+                compiled.label = label;
+                compiled.word = Some(Dictionary::get_full_name(&stmt.name.lexeme, &self.mod_name));
+                compiled.entry = stmt.has_attribute(TokenType::Entry);
 
                 self.dictionary.define(&stmt.name.lexeme,
                     &self.mod_name,
                     &Object::Callable(Box::new(CompiledCallable {
                         compiled: compiled.clone(),
-                        mode: stmt.mode
+                        mode: stmt.mode,
+                        inline,
+                        effect
                     })));
                 Ok(compiled)
             },
             DefineMode::Inline => {
+                let effect = self.word_effect(stmt)?;
                 let compiled = self.execute(&mut stmt.body)?;
                 self.dictionary.define(&stmt.name.lexeme,
                     &self.mod_name,
                     &Object::Callable(Box::new(CompiledCallable {
                         compiled,
-                        mode: stmt.mode
+                        mode: stmt.mode,
+                        inline: None,
+                        effect
                     })));
                 Ok(Compiled::new(vec![]))
             },
@@ -273,6 +982,49 @@ impl StmtVisitor for Compiler {
         }
     }
 
+    fn visit_let(&mut self, stmt: &mut LetStmt) -> BoxResult<Compiled> {
+        // the storage is a fragment of its own so unused variables are removed
+        let name = Object::Word(stmt.name.lexeme.clone());
+        let size = ("__SIZE__", stmt.size().to_string());
+        let mut compiled = self.call_word_with(stmt.token(), "__var", &name, &[size])?;
+        compiled.word = Some(Dictionary::get_full_name(&stmt.name.lexeme, &self.mod_name));
+
+        self.dictionary.define(&stmt.name.lexeme,
+            &self.mod_name,
+            &Object::Callable(Box::new(VarCallable { width: stmt.width_name() })));
+        Ok(compiled)
+    }
+
+    fn visit_forward(&mut self, stmt: &mut ForwardStmt) -> BoxResult<Compiled> {
+        let full_name = Dictionary::get_full_name(&stmt.name.lexeme, &self.mod_name);
+        self.forwards.entry(full_name).or_insert_with(|| stmt.name.clone());
+        self.dictionary.define(&stmt.name.lexeme,
+            &self.mod_name,
+            &Object::Callable(Box::new(ForwardCallable { effect: stmt.effect })));
+        Ok(Compiled::new(vec![]))
+    }
+
+    fn visit_cond(&mut self, stmt: &mut CondStmt) -> BoxResult<Compiled> {
+        // conditions are usually resolved before compiling
+        let mut branch = self.taken_branch(stmt)?;
+        self.resolve(&mut branch)?;
+        let mut compiled = Compiled::new(vec![]);
+        for stmt in &mut branch {
+            compiled.append(&mut self.execute(stmt)?);
+        }
+        Ok(compiled)
+    }
+
+    fn visit_expand(&mut self, stmt: &mut ExpandStmt) -> BoxResult<Compiled> {
+        let id = self.expansions.get();
+        self.expansions.set(id + 1);
+
+        let outer = self.expansion.replace(id);
+        let compiled = self.compile_body(&mut stmt.body);
+        self.expansion = outer;
+        compiled
+    }
+
     fn visit_if(&mut self, stmt: &mut IfStmt) -> BoxResult<Compiled> {
         let mut compiled = Compiled::new(vec![]);
 
@@ -280,17 +1032,17 @@ impl StmtVisitor for Compiler {
         match &mut stmt.else_block {
             Some(else_block) => {
                 // if-else
-                compiled.data.append(&mut self.call_word(token.clone(), "__ifelse", &Object::Nil)?.data);
-                compiled.data.append(&mut self.execute(&mut stmt.then_block)?.data);
-                compiled.data.append(&mut self.call_word(token.clone(), "__else", &Object::Nil)?.data);
-                compiled.data.append(&mut self.execute(else_block)?.data);
-                compiled.data.append(&mut self.call_word(token.clone(), "__then", &Object::Nil)?.data);
+                compiled.append(&mut self.call_word(token.clone(), "__ifelse", &Object::Nil)?);
+                compiled.append(&mut self.execute(&mut stmt.then_block)?);
+                compiled.append(&mut self.call_word(token.clone(), "__else", &Object::Nil)?);
+                compiled.append(&mut self.execute(else_block)?);
+                compiled.append(&mut self.call_word(token.clone(), "__then", &Object::Nil)?);
             },
             _ => {
                 // if only
-                compiled.data.append(&mut self.call_word(token.clone(), "__if", &Object::Nil)?.data);
-                compiled.data.append(&mut self.execute(&mut stmt.then_block)?.data);
-                compiled.data.append(&mut self.call_word(token.clone(), "__then", &Object::Nil)?.data);
+                compiled.append(&mut self.call_word(token.clone(), "__if", &Object::Nil)?);
+                compiled.append(&mut self.execute(&mut stmt.then_block)?);
+                compiled.append(&mut self.call_word(token.clone(), "__then", &Object::Nil)?);
             }
         }
         return Ok(compiled);
@@ -300,13 +1052,101 @@ impl StmtVisitor for Compiler {
         let mut compiled = Compiled::new(vec![]);
 
         let token = stmt.token();
-        compiled.data.append(&mut self.call_word(token.clone(), "__loop", &Object::Nil)?.data);
-        compiled.data.append(&mut self.execute(&mut stmt.block)?.data);
-        compiled.data.append(&mut self.call_word(token.clone(), "__until", &Object::Nil)?.data);
+        compiled.append(&mut self.call_word(token.clone(), "__loop", &Object::Nil)?);
+        compiled.append(&mut self.execute(&mut stmt.block)?);
+        compiled.append(&mut self.call_word(token.clone(), "__until", &Object::Nil)?);
+
+        return Ok(compiled);
+    }
+
+    fn visit_while(&mut self, stmt: &mut WhileStmt) -> BoxResult<Compiled> {
+        let mut compiled = Compiled::new(vec![]);
+
+        let token = stmt.token();
+        compiled.append(&mut self.call_word(token.clone(), "__begin", &Object::Nil)?);
+        compiled.append(&mut self.execute(&mut stmt.condition)?);
+        compiled.append(&mut self.call_word(token.clone(), "__while", &Object::Nil)?);
+        compiled.append(&mut self.execute(&mut stmt.block)?);
+        compiled.append(&mut self.call_word(token.clone(), "__repeat", &Object::Nil)?);
+
+        return Ok(compiled);
+    }
+
+    fn visit_case(&mut self, stmt: &mut CaseStmt) -> BoxResult<Compiled> {
+        let mut compiled = Compiled::new(vec![]);
+
+        let token = stmt.token();
+        let id = self.cases.get();
+        self.cases.set(id + 1);
+        let case_var = ("__CASE__", id.to_string());
+        let of_vars = |of: String| [case_var.clone(), ("__OF__", of)];
+
+        compiled.append(&mut self.call_word_with(token.clone(), "__case", &Object::Nil, std::slice::from_ref(&case_var))?);
+        match self.case_values(stmt) {
+            Some(values) => {
+                // the table points every value in the range to the first
+                // branch with that value. gaps point to the default
+                let min = *values.iter().min().unwrap_or(&0);
+                let max = *values.iter().max().unwrap_or(&0);
+                compiled.append(&mut self.call_word_with(token.clone(), "__casetable", &Object::Nil,
+                    &[case_var.clone(), ("__MIN__", min.to_string()), ("__MAX__", max.to_string())])?);
+                for value in min..=max {
+                    let of = match values.iter().position(|v| *v == value) {
+                        Some(i) => i.to_string(),
+                        None => "default".to_string()
+                    };
+                    compiled.append(&mut self.call_word_with(token.clone(), "__caseentry", &Object::Nil, &of_vars(of))?);
+                }
+
+                for (i, branch) in stmt.branches.iter_mut().enumerate() {
+                    compiled.append(&mut self.call_word_with(branch.token.clone(), "__caseof", &Object::Nil, &of_vars(i.to_string()))?);
+                    compiled.append(&mut self.execute(&mut branch.block)?);
+                    compiled.append(&mut self.call_word_with(branch.token.clone(), "__endof", &Object::Nil, &of_vars(i.to_string()))?);
+                }
+                compiled.append(&mut self.call_word_with(token.clone(), "__casedefault", &Object::Nil, std::slice::from_ref(&case_var))?);
+            },
+            None => {
+                for (i, branch) in stmt.branches.iter_mut().enumerate() {
+                    compiled.append(&mut self.execute(&mut branch.value)?);
+                    compiled.append(&mut self.call_word_with(branch.token.clone(), "__of", &Object::Nil, &of_vars(i.to_string()))?);
+                    compiled.append(&mut self.execute(&mut branch.block)?);
+                    compiled.append(&mut self.call_word_with(branch.token.clone(), "__endof", &Object::Nil, &of_vars(i.to_string()))?);
+                }
+            }
+        }
+        compiled.append(&mut self.execute(&mut stmt.default)?);
+        compiled.append(&mut self.call_word_with(token.clone(), "__endcase", &Object::Nil, &[case_var])?);
+
+        return Ok(compiled);
+    }
+
+    fn visit_do(&mut self, stmt: &mut DoStmt) -> BoxResult<Compiled> {
+        let mut compiled = Compiled::new(vec![]);
+
+        let token = stmt.token();
+        let end = if stmt.plus { "__doplusloop" } else { "__doloop" };
+        compiled.append(&mut self.call_word(token.clone(), "__do", &Object::Nil)?);
+        compiled.append(&mut self.execute(&mut stmt.block)?);
+        compiled.append(&mut self.call_word(token.clone(), end, &Object::Nil)?);
 
         return Ok(compiled);
     }
 
+    fn visit_index(&mut self, stmt: &mut IndexStmt) -> BoxResult<Compiled> {
+        self.call_word(stmt.token(), "__i", &Object::Nil)
+    }
+
+    fn visit_exit(&mut self, stmt: &mut ExitStmt) -> BoxResult<Compiled> {
+        // __exit is optional, return works for words that need no cleanup
+        let exit = Token::new(TokenType::Word, Object::Nil, "__exit", 0, 0, "");
+        let name = match self.dictionary.get_any(&exit, self.build_imports("__exit")) {
+            Ok(_) => "__exit",
+            Err(_) => "return"
+        };
+        self.exits = true;
+        self.call_word(stmt.token(), name, &Object::Word(stmt.word.lexeme.clone()))
+    }
+
     fn visit_impoprt(&mut self, stmt: &mut ImportStmt) -> BoxResult<Compiled> {
         // compile a module, get all the code and
         // return the compilation output
@@ -318,26 +1158,57 @@ impl StmtVisitor for Compiler {
             _ => return Err(Box::new(ExecError::new(ErrorType::TypeError, stmt.token())))
         };
 
-        let fs = LocalFileSystem;
-        let source = fs.read_file(path)?;
+        // the parser already parsed the file unless it had an error
+        let source = match &stmt.module {
+            Some(module) => module.source.clone(),
+            None => match self.filesystem.read_file(path) {
+                Ok(source) => source,
+                // invalid utf-8 already points into the imported file
+                Err(err) => return match err.downcast::<ExecError>() {
+                    Ok(err) => Err(err),
+                    Err(_) => Err(Box::new(ExecError::new(ErrorType::IOError, stmt.token())))
+                }
+            }
+        };
 
         // only compile if we do not have the compiled code already!
         if !self.module_tracker.as_ref().borrow().contains_key(&source) {
-            let mut compiler = Compiler::new(&source, &path)?;
+            let mut compiler = match &stmt.module {
+                Some(module) => {
+                    let mut compiler = Compiler::with(module.stmts.clone(), path);
+                    compiler.filesystem = self.filesystem.clone();
+                    compiler
+                },
+                None => Compiler::with_filesystem(&source, path, self.filesystem.clone())?
+            };
             compiler.module_tracker = self.module_tracker.clone();
-            let mut compiled = compiler.compile()?;
-
-            let flattened = Compiled::flatten_bytes(&mut compiled);
+            compiler.optimizer = self.optimizer.clone();
+            compiler.keep_all = self.keep_all;
+            compiler.check_stack = self.check_stack;
+            compiler.outlines = self.outlines.clone();
+            compiler.cases = self.cases.clone();
+            compiler.expansions = self.expansions.clone();
+            compiler.uses = self.uses.clone();
+            let mut compiled = compiler.compile_unit()?;
 
             // keep track of compilation result in the tracker for later use
-            self.module_tracker.as_ref().borrow_mut().insert(source.clone(), compiler.dictionary.clone());
+            self.module_tracker.as_ref().borrow_mut().insert(source.clone(), CompiledModule {
+                dictionary: compiler.dictionary.clone(),
+                peephole: compiler.peephole.clone()
+            });
             // merge dictionaries
             self.dictionary.as_mut().extend(compiler.dictionary.as_ref());
+            self.peephole.extend(compiler.peephole);
 
-            return Ok(flattened);
+            // the module's words are kept as separate fragments
+            // so unused words can be removed later
+            self.imported.append(&mut compiled);
+            return Ok(Compiled::new(vec![]));
         } else {
             // if we already did the compilation just add it
-            self.dictionary.as_mut().extend(&self.module_tracker.borrow()[&source]);
+            let module = self.module_tracker.borrow()[&source].clone();
+            self.dictionary.as_mut().extend(&module.dictionary);
+            self.peephole.extend(module.peephole);
             Ok(Compiled::new(vec![]))
         }
     }
@@ -360,7 +1231,10 @@ impl StmtVisitor for Compiler {
 
     fn visit_asm(&mut self, stmt: &mut AsmStmt) -> BoxResult<Compiled> {
         match &stmt.code {
-            Object::Str(code) => Ok(Compiled::new(code.clone().into_bytes())),
+            Object::Str(code) => match self.expansion {
+                Some(id) => Ok(Compiled::new(code.replace("__MACRO__", &id.to_string()).into_bytes())),
+                None => Ok(Compiled::new(code.clone().into_bytes()))
+            },
             _ => Err(Box::new(ExecError::new(ErrorType::TypeError, stmt.token())))
         }
     }
@@ -374,6 +1248,19 @@ impl StmtVisitor for Compiler {
             _ => return Err(Box::new(ExecError::new(ErrorType::TypeError, stmt.token())))
         }
     }
+
+    fn visit_peephole(&mut self, stmt: &mut PeepholeStmt) -> BoxResult<Compiled> {
+        let first = self.expand_template(&stmt.first)?;
+        let second = self.expand_template(&stmt.second)?;
+
+        let mut replacement = vec![];
+        for word in &stmt.replacement {
+            replacement.append(&mut self.expand_template(word)?);
+        }
+
+        self.peephole.push(PeepholeRule::new(first, second, replacement));
+        Ok(Compiled::new(vec![]))
+    }
 }
 
 impl ExprVisitor for Compiler {
@@ -416,6 +1303,11 @@ impl ExprVisitor for Compiler {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::filesystem::MemoryFileSystem;
+
+    fn compiler_with_files(source: &str, files: Vec<(&str, &str)>) -> Compiler {
+        Compiler::with_filesystem(source, "main.fth", Rc::new(MemoryFileSystem::with(files))).unwrap()
+    }
 
     #[test]
     fn it_should_define_inline_inx() {
@@ -426,6 +1318,112 @@ mod tests {
         assert_eq!(output, "label: inx\nrts\n".to_string()) ;
     }
 
+    #[test]
+    fn it_should_emit_raw_asm() {
+        let mut compiler = Compiler::new(":i ++ :asm {{
+                lda \"\\\"
+                  inx
+            }} ; ++", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "lda \"\\\"\n  inx\n\n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_expand_parsing_words() {
+        let mut compiler = Compiler::new(":parsing byte 1 \".byte \" swap concat emit_asm ;
+            :i main byte 5 ; main", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, ".byte 5\n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_compile_do_loops() {
+        let mut compiler = Compiler::new(":i push_default :asm \"push __ARG__\n\" ;
+            :i __do :asm \"do\n\" ;
+            :i __doloop :asm \"doloop\n\" ;
+            :i __doplusloop :asm \"doplusloop\n\" ;
+            :i __i :asm \"index\n\" ;
+            10 0 do i loop
+            10 0 do 2 +loop", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "push 10\n\npush 0\n\ndo\nindex\ndoloop\n\npush 10\n\npush 0\n\ndo\npush 2\ndoplusloop\n\n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_compile_while_loops() {
+        let mut compiler = Compiler::new(":i push_default :asm \"push __ARG__\n\" ;
+            :i __begin :asm \"begin\n\" ;
+            :i __while :asm \"while\n\" ;
+            :i __repeat :asm \"repeat\n\" ;
+            begin 1 while 2 repeat", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "begin\npush 1\nwhile\npush 2\nrepeat\n\n".to_string()) ;
+    }
+
+    const CASE_TEMPLATES: &str = ":i push_default :asm \"push __ARG__\n\" ;
+            :i __case :asm \"case __CASE__\n\" ;
+            :i __of :asm \"of __CASE__ __OF__\n\" ;
+            :i __endof :asm \"endof __CASE__ __OF__\n\" ;
+            :i __endcase :asm \"endcase __CASE__\n\" ;";
+
+    #[test]
+    fn it_should_compile_case() {
+        let mut compiler = Compiler::new(&format!("{}
+            :i main case 1 of 10 endof 2 of endof 3 endcase ; main", CASE_TEMPLATES), "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "case 0\npush 1\nof 0 0\npush 10\nendof 0 0\n\
+            push 2\nof 0 1\nendof 0 1\npush 3\nendcase 0\n\n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_compile_case_tables() {
+        let mut compiler = Compiler::new(&format!("{}
+            :i __casetable :asm \"table __MIN__ __MAX__\n\" ;
+            :i __caseentry :asm \".word __OF__\n\" ;
+            :i __caseof :asm \"label __OF__\n\" ;
+            :i __casedefault :asm \"default\n\" ;
+            :c TWO 2 ;
+            :i main case 1 of endof TWO of endof 4 of endof endcase ;
+            :i small case 1 of endof 2 of endof endcase ;
+            main small", CASE_TEMPLATES), "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "case 0\ntable 1 4\n.word 0\n.word 1\n.word default\n.word 2\n\
+            label 0\nendof 0 0\nlabel 1\nendof 0 1\nlabel 2\nendof 0 2\ndefault\nendcase 0\n\n\
+            case 1\npush 1\nof 1 0\nendof 1 0\npush 2\nof 1 1\nendof 1 1\nendcase 1\n\n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_compile_variables() {
+        let mut compiler = Compiler::new(":i compile :asm \"__ARG__:\n\" ;
+            :i call :asm \"jsr __ARG__\n\" ;
+            :i return :asm \"rts\" ;
+            :i __var :asm \"__ARG__: .res __SIZE__\" ;
+            :i __tick :asm \"push __ARG__\n\" ;
+            :i __fetch_i8 :asm \"fetch8 __ARG__\n\" ;
+            :i __store_i16 :asm \"store16 __ARG__\n\" ;
+            :let x :i8
+            :let y :i16
+            :let unused :i8
+            : main x @ y ! x ;
+            main x @", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "x: .res 1\ny: .res 2\nmain:\nfetch8 x\nstore16 y\npush x\nrts\njsr main\n\nfetch8 x\n\n".to_string()) ;
+    }
+
     #[test]
     fn it_should_call_word() {
         let mut compiler = Compiler::new(":i compile :asm \"__ARG__:\n\" ;
@@ -447,6 +1445,7 @@ mod tests {
             :i push_default :asm \"lda #__ARG__ pha \" ;
             1 if 2 then
             ", "").unwrap();
+        compiler.optimizer.disable("dead-branch");
         let result = compiler.compile().unwrap();
         let output = Compiled::flatten(result).unwrap();
 
@@ -462,6 +1461,7 @@ mod tests {
             :i push_default :asm \"lda #__ARG__ pha \" ;
             1 if 2 else 3 then
             ", "").unwrap();
+        compiler.optimizer.disable("dead-branch");
         let result = compiler.compile().unwrap();
         let output = Compiled::flatten(result).unwrap();
 
@@ -495,6 +1495,369 @@ mod tests {
         assert_eq!(output, "lda #4 pha\n".to_string()) ;
     }
 
+    #[test]
+    fn it_should_fold_constants() {
+        let mut compiler = Compiler::new("
+            :i push_default :asm \"lda #__ARG__ pha\" ;
+            1 2 + 4 *
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "lda #12 pha\n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_remove_unused_words() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            :i __tick :asm \"lda __ARG__ \" ;
+            : unused :asm \"unused \" ;
+            : used :asm \"used \" ;
+            : indirect used ;
+            : ticked :asm \"ticked \" ;
+            : nmi :entry :asm \"rti \" ;
+            indirect
+            ' ticked
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output,
+            "used: used rts \nindirect: jsr used rts \nticked: ticked rts \nnmi: rti rts \njsr indirect \nlda ticked \n"
+            .to_string()) ;
+    }
+
+    #[test]
+    fn it_should_keep_all_words() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i return :asm \"rts \" ;
+            : unused :asm \"unused \" ;
+            ", "").unwrap();
+        compiler.keep_all = true;
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "unused: unused rts \n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_inline_small_words() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            : small :asm \"inx \" ;
+            : large :asm \"lda #100 sta 200 \" ;
+            : kept :noinline :asm \"inx \" ;
+            small small large large kept kept
+            ", "").unwrap();
+        compiler.optimizer.level = 2;
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output,
+            "large: lda #100 sta 200 rts \nkept: inx rts \ninx \ninx \njsr large \njsr large \njsr kept \njsr kept \n"
+            .to_string()) ;
+    }
+
+    #[test]
+    fn it_should_inline_words_used_once() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            : once :asm \"lda #100 sta 200 \" ;
+            once
+            ", "").unwrap();
+        compiler.optimizer.level = 3;
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "lda #100 sta 200 \n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_count_uses_in_the_whole_program() {
+        let mut compiler = compiler_with_files(":import \"m.fth\" m::helper m::once", vec![
+            ("m.fth", ":i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            :mod m
+            : helper :asm \"lda #100 sta 200 lda #100 sta 201 \" ;
+            : once helper ;")
+        ]);
+        compiler.optimizer.level = 3;
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        // helper is used once in the module and once in this file
+        assert_eq!(output,
+            "m__mod__helper: lda #100 sta 200 lda #100 sta 201 rts \njsr m__mod__helper \njsr m__mod__helper \n"
+            .to_string()) ;
+    }
+
+    #[test]
+    fn it_should_not_inline_below_level_2() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            : small :asm \"inx \" ;
+            small
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "small: inx rts \njsr small \n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_use_tail_calls() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i tailcall :asm \"jmp __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            : a :asm \"a \" ;
+            : b a a ;
+            : c a :asm \"c \" ;
+            b c
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output,
+            "a: a rts \nb: jsr a jmp a \nc: jsr a c rts \njsr b \njsr c \n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_not_use_tail_calls_without_template() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            : a :asm \"a \" ;
+            : b a ;
+            b
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "a: a rts \nb: jsr a rts \njsr b \n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_apply_peephole_rules() {
+        let mut compiler = Compiler::new("
+            :i push_i8im :asm \"sta FTHSTACK,x\ninx\n\" ;
+            :i pull_i8 :asm \"dex\nlda FTHSTACK,x\n\" ;
+            :i push_default :asm \"lda #__ARG__\n\" push_i8im ;
+            :i + pull_i8 :asm \"adc #1\n\" push_i8im ;
+            :peephole push_i8im pull_i8 ;
+            1 +
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "lda #1\n\nadc #1\nsta FTHSTACK,x\ninx\n\n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_use_peephole_replacement() {
+        let mut compiler = Compiler::new("
+            :i a :asm \"a \" ;
+            :i b :asm \"b \" ;
+            :i c :asm \"c \" ;
+            :peephole a b c c ;
+            a b b
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "c c \nb \n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_not_allow_peephole_templates_with_arguments() {
+        for (rule, word) in &[("push_default pull_i8", "push_default"), ("pull_i8 branch", "branch")] {
+            let mut compiler = Compiler::new(&format!("
+                :i push_default :asm \"lda #__ARG__\n\" ;
+                :i pull_i8 :asm \"dex\n\" ;
+                :i branch :asm \"bne L__LINE__\n\" ;
+                :peephole {} ;
+                ", rule), "").unwrap();
+            let errors = match compiler.compile() {
+                Err(errors) => errors.errors,
+                Ok(_) => panic!("Expected errors")
+            };
+
+            let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+            assert_eq!(errors_id, vec![format!("type: InvalidPeephole; lexeme: {}", word)]);
+        }
+    }
+
+    #[test]
+    fn it_should_remove_dead_branches() {
+        let mut compiler = Compiler::new("
+            :i push_default :asm \"lda #__ARG__ pha\" ;
+            :c DEBUG 0 ;
+            DEBUG if undefined then
+            1 if 2 else undefined then
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "lda #2 pha\n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_not_fold_imported_words() {
+        let mut compiler = compiler_with_files(":import \"add.fth\" 200 100 +", vec![
+            ("add.fth", ":i push_default :asm \"lda #__ARG__ pha\" ;
+            :i + :asm \"pla adc\" ;")
+        ]);
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "lda #200 pha\nlda #100 pha\npla adc\n".to_string()) ;
+    }
+
+    fn check_stack(source: &str) -> Result<Compiler, ErrorType> {
+        let mut compiler = Compiler::new(&format!("
+            :i compile :asm \"__ARG__:\" ;
+            :i call :asm \"jsr __ARG__\" ;
+            :i return :asm \"rts\" ;
+            :i push_default :asm \"lda #__ARG__ pha\" ;
+            :i + :effect 2 1 :asm \"pla adc\" ;
+            :i drop :effect 1 0 :asm \"pla\" ;
+            {}", source), "").unwrap();
+        compiler.check_stack = true;
+        match compiler.compile() {
+            Ok(_) => Ok(compiler),
+            Err(err) => Err(err.errors[0].downcast_ref::<ExecError>().unwrap().error_type)
+        }
+    }
+
+    #[test]
+    fn it_should_check_stack_effects() {
+        let compiler = check_stack(": add3 + + ; 1 2 3 add3 drop").unwrap();
+        assert_eq!(compiler.max_depth(), Some(3));
+
+        let compiler = check_stack(": add3 :effect 3 1 + + ; 1 2 3 add3 4 5 drop drop drop").unwrap();
+        assert_eq!(compiler.max_depth(), Some(3));
+
+        let compiler = check_stack(":i raw :asm \"pha\" ; 1 raw").unwrap();
+        assert_eq!(compiler.max_depth(), None);
+    }
+
+    #[test]
+    fn it_should_report_stack_errors() {
+        assert_eq!(check_stack(": add3 + + ; 1 2 add3").err(), Some(ErrorType::StackUnderflow));
+        assert_eq!(check_stack(": add3 :effect 3 2 + + ;").err(), Some(ErrorType::StackEffectMismatch));
+        assert_eq!(check_stack(": bad :effect 1 1 + ;").err(), Some(ErrorType::StackUnderflow));
+        assert_eq!(check_stack(": bad if 1 then ;").err(), Some(ErrorType::UnbalancedBranches));
+    }
+
+    fn outline(source: &str, enabled: bool) -> String {
+        let mut compiler = Compiler::new(&format!("
+            :i compile :asm \"__ARG__:\n\" ;
+            :i call :asm \"jsr __ARG__\n\" ;
+            :i return :asm \"rts\n\" ;
+            :i push_default :asm \"lda #__ARG__\npha\n\" ;
+            {}", source), "").unwrap();
+        compiler.optimizer.optimize_for_size();
+        if !enabled {
+            compiler.optimizer.disable("outline");
+        }
+        let result = compiler.compile().unwrap();
+        Compiled::flatten(result).unwrap()
+    }
+
+    #[test]
+    fn it_should_outline_repeated_sequences() {
+        let source = ": a 1 2 3 4 ; : b 5 1 2 3 4 ; : c 1 2 3 4 5 ; a b c";
+        let output = outline(source, true);
+
+        assert_eq!(output.matches("__outline_0:").count(), 1);
+        assert_eq!(output.matches("jsr __outline_0").count(), 3);
+        assert!(output.len() < outline(source, false).len());
+    }
+
+    #[test]
+    fn it_should_not_outline_part_of_a_variable_access() {
+        let output = outline(":i __var :asm \"__ARG__: .res __SIZE__\n\" ;
+            :i __tick :asm \"push __ARG__\n\" ;
+            :i __fetch_i8 :asm \"fetch8 __ARG__\n\" ;
+            :i @ :asm \"fetch\n\" ;
+            :i big :asm \"lda #1\nlda #2\nlda #3\nlda #4\n\" ;
+            :let v :i8
+            : a big v @ ; : b big v @ ; : c big v @ ; a b c", true);
+
+        assert!(output.contains("fetch8 v"));
+        assert!(!output.contains("push v"));
+        assert!(!output.contains("fetch\n"));
+    }
+
+    #[test]
+    fn it_should_outline_with_imported_templates() {
+        let mut compiler = compiler_with_files(":import \"lib.fth\"
+            : a 1 2 3 4 ; : b 5 1 2 3 4 ; : c 1 2 3 4 5 ; a b c", vec![
+            ("lib.fth", ":i compile :asm \"__ARG__:\n\" ;
+            :i call :asm \"jsr __ARG__\n\" ;
+            :i return :asm \"rts\n\" ;
+            :i push_default :asm \"lda #__ARG__\npha\n\" ;")
+        ]);
+        compiler.optimizer.optimize_for_size();
+        let output = Compiled::flatten(compiler.compile().unwrap()).unwrap();
+
+        assert_eq!(output.matches("jsr __outline_0").count(), 3);
+    }
+
+    #[test]
+    fn it_should_not_know_sizes_of_generated_statements() {
+        let mut compiler = Compiler::new(":i compile :asm \"__ARG__:\n\" ;
+            :i return :asm \"rts\n\" ;
+            :i push_default :asm \"lda #__ARG__\npha\n\" ;
+            :parsing lit 1 emit_literal ;
+            : a lit 1 lit 1000 ;", "").unwrap();
+        compiler.keep_all = true;
+        compiler.compile().unwrap();
+
+        // both literals have the position of emit_literal
+        assert!(compiler.sizes.values().any(|size| *size == StmtSize::Unknown));
+    }
+
+    #[test]
+    fn it_should_not_outline_if_it_does_not_save_bytes() {
+        let source = ": a 1 2 ; : b 1 2 ; a b";
+        assert_eq!(outline(source, true), outline(source, false));
+    }
+
+    #[test]
+    fn it_should_alias_identical_words() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__:\n\" ;
+            :i call :asm \"jsr __ARG__\n\" ;
+            :i return :asm \"rts\n\" ;
+            :i alias :asm \"__ARG__ = __TARGET__\n\" ;
+            :i push_default :asm \"lda #__ARG__\n\" ;
+            : a 1 2 ;
+            :mod m
+            : b 1 2 ;
+            a b
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "a:\nlda #1\nlda #2\nrts\n\nm__mod__b = a\n\njsr a\n\njsr m__mod__b\n\n".to_string());
+        assert_eq!(compiler.dedup.aliases, vec![("m::b".to_string(), "a".to_string())]);
+        assert_eq!(compiler.dedup.saved, 29 - 14);
+    }
+
     #[test]
     fn it_should_use_mod_keyword() {
         let mut compiler = Compiler::new("
@@ -523,6 +1886,7 @@ mod tests {
             :i call :asm \":\" ;
             : mod :asm \"mod\" ;
             ", "").unwrap();
+        compiler.keep_all = true;
         let result = compiler.compile().unwrap();
         let output = Compiled::flatten(result).unwrap();
 
@@ -627,4 +1991,194 @@ mod tests {
             "Test__mod__a: lda 1 rts \nTest__mod__b: lda 2 rts \nTest__mod__c: lda 3 rts \nOther__mod__d: lda 4 rts \n jsr Test__mod__a \n jsr Test__mod__b \n jsr Test__mod__c \n jsr Other__mod__d \n jsr Test__mod__a \n jsr Test__mod__c \n jsr Other__mod__d \n"
             .to_string()) ;
     }
+
+    #[test]
+    fn it_should_call_itself_with_recurse() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            : a :asm \"a \" recurse :asm \"b \" ;
+            a
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "a: a jsr a b rts \njsr a \n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_call_forward_declared_words() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i tailcall :asm \"jmp __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            :forward b ;
+            : a :asm \"a \" b ;
+            : b :asm \"b \" a ;
+            a
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "a: a jmp b \nb: b jmp a \njsr a \n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_report_undefined_forward_words() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            :forward b ;
+            :forward c ;
+            : a b ;
+            :i c ;
+            a
+            ", "").unwrap();
+        let errors = match compiler.compile() {
+            Err(errors) => errors.errors,
+            Ok(_) => panic!("Expected errors")
+        };
+
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec!["type: InvalidForward; lexeme: c".to_string()]);
+
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            :forward b ;
+            : a b ;
+            a
+            ", "").unwrap();
+        let errors = match compiler.compile() {
+            Err(errors) => errors.errors,
+            Ok(_) => panic!("Expected errors")
+        };
+
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec!["type: UndefinedForward; lexeme: b".to_string()]);
+    }
+
+    #[test]
+    fn it_should_exit_early() {
+        let source = "
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            :i push_default :asm \"lda __ARG__ \" ;
+            {}
+            :c ten 10 exit 20 ;
+            : a :asm \"a \" exit :asm \"b \" ;
+            a ten
+            ";
+        let mut compiler = Compiler::new(&source.replace("{}", ""), "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+        assert_eq!(output, "a: a rts b rts \njsr a \nlda 10 \n".to_string()) ;
+
+        // words that exit early are never inlined
+        let mut compiler = Compiler::new(&source.replace("{}", ":i __exit :asm \"x \" ;"), "").unwrap();
+        compiler.optimizer.optimize_for_size();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+        assert_eq!(output, "a: a x b rts \njsr a \nlda 10 \n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_compile_the_taken_branch_only() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            :i push_default :asm \"lda __ARG__ \" ;
+            :c NES 1 ;
+            :if NES ;
+                : out :asm \"nes \" ;
+            :else
+                : out :asm \"other \" ;
+            :endif
+            : a :if NES 1 - ; undefined :else 1 :endif ;
+            :ifdef PAL 2 :else 3 :endif
+            out a
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "out: nes rts \na: lda 1 rts \nlda 3 \njsr out \njsr a \n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_use_module_constants_in_conditions() {
+        let mut compiler = Compiler::new("
+            :i push_default :asm \"lda __ARG__ \" ;
+            :mod m
+            :c DEBUG 0 ;
+            :if DEBUG ; 1 :else 2 :endif
+            :if m::DEBUG ; 3 :else 4 :endif
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "lda 2 \nlda 4 \n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_keep_labels_of_macros_unique() {
+        let mut compiler = Compiler::new("
+            :i compile :asm \"__ARG__: \" ;
+            :i call :asm \"jsr __ARG__ \" ;
+            :i return :asm \"rts \" ;
+            :i push_default :asm \"lda __ARG__ \" ;
+            :i __if :asm \"beq then__LINE__ \" ;
+            :i __then :asm \"then__LINE__: \" ;
+            :macro when body ;
+                if body then :asm \"m__MACRO__ \"
+            ;
+            : a 1 when [ 2 ] 0 when 3 ;
+            a
+            ", "").unwrap();
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output,
+            "a: lda 1 beq then9_0 lda 2 then9_0: m0 lda 0 beq then9_1 lda 3 then9_1: m1 rts \njsr a \n".to_string()) ;
+    }
+
+    #[test]
+    fn it_should_only_report_import_errors_of_compiled_imports() {
+        let result = compiler_with_files(":i push_default :asm \"push __ARG__\n\" ;
+            :if 0 ; :import \"missing.fth\" :endif
+            1", vec![]).compile().unwrap();
+        assert_eq!(Compiled::flatten(result).unwrap(), "push 1\n\n".to_string());
+
+        let errors = match compiler_with_files(":import \"missing.fth\"", vec![]).compile() {
+            Err(errors) => errors,
+            Ok(_) => panic!("Expected error")
+        };
+        assert_eq!(format!("{}", errors), "IO Error in main.fth:1:9 (\"missing.fth\")\n");
+
+        let errors = match compiler_with_files(":import \"broken.fth\"", vec![("broken.fth", ": ;")]).compile() {
+            Err(errors) => errors,
+            Ok(_) => panic!("Expected error")
+        };
+        assert_eq!(format!("{}", errors), "Expected name in broken.fth:1:3 (;)\n");
+    }
+
+    #[test]
+    fn it_should_keep_module_words_used_from_asm() {
+        let mut compiler = compiler_with_files(":import \"m.fth\" :asm \"jmp m__mod__main\n\"", vec![
+            ("m.fth", ":i compile :asm \"__ARG__: \" ;
+            :i return :asm \"rts\n\" ;
+            :mod m
+            : main :asm \"inx \" ;
+            : unused :asm \"dex \" ;")
+        ]);
+        let result = compiler.compile().unwrap();
+        let output = Compiled::flatten(result).unwrap();
+
+        assert_eq!(output, "m__mod__main: inx rts\n\njmp m__mod__main\n\n".to_string());
+    }
 }
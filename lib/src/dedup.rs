@@ -0,0 +1,97 @@
+use super::stmt::Compiled;
+use super::error::BoxResult;
+use std::collections::HashMap;
+
+/// the words that were replaced by an alias
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DedupReport {
+    // (duplicate, word it is an alias of)
+    pub aliases: Vec<(String, String)>,
+    pub saved: usize
+}
+
+/// emits words with the same code only once.
+/// bodies are compared without the label emitted by compile
+pub struct Deduplicator;
+
+impl Deduplicator {
+    /// replaces every later definition of a body by the code
+    /// returned by alias(duplicate, word) if it is smaller
+    pub fn dedup(output: &mut [Compiled],
+        alias: &mut dyn FnMut(&str, &str) -> BoxResult<Compiled>) -> BoxResult<DedupReport> {
+        let mut report = DedupReport::default();
+        let mut bodies: HashMap<Vec<u8>, String> = HashMap::new();
+
+        for fragment in output.iter_mut() {
+            let word = match &fragment.word {
+                Some(word) => word.clone(),
+                None => continue
+            };
+
+            let body = fragment.data[fragment.label..].to_vec();
+            let target = match bodies.get(&body) {
+                Some(target) => target.clone(),
+                None => {
+                    bodies.insert(body, word);
+                    continue;
+                }
+            };
+
+            let mut replacement = alias(&word, &target)?;
+            if replacement.data.len() >= fragment.data.len() {
+                continue;
+            }
+
+            report.saved += fragment.data.len() - replacement.data.len();
+            replacement.refs.push(target.clone());
+            replacement.word = Some(word.clone());
+            replacement.entry = fragment.entry;
+            *fragment = replacement;
+            report.aliases.push((word, target));
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(name: &str, body: &str) -> Compiled {
+        let mut compiled = Compiled::new(format!("{}: {}", name, body).into_bytes());
+        compiled.word = Some(name.into());
+        compiled.label = name.len() + 2;
+        compiled
+    }
+
+    fn alias(word: &str, target: &str) -> BoxResult<Compiled> {
+        Ok(Compiled::new(format!("{} = {}", word, target).into_bytes()))
+    }
+
+    #[test]
+    fn it_should_alias_duplicate_bodies() {
+        let mut output = vec![
+            word("a", "lda #1 rts"),
+            Compiled::new(b"lda #1 rts".to_vec()),
+            word("b", "lda #2 rts"),
+            word("c", "lda #1 rts"),
+            word("d", "lda #2 rts")
+        ];
+        let report = Deduplicator::dedup(&mut output, &mut alias).unwrap();
+
+        assert_eq!(report.aliases, vec![("c".to_string(), "a".to_string()), ("d".into(), "b".into())]);
+        assert_eq!(report.saved, 2 * (13 - 5));
+        assert_eq!(Compiled::flatten(output).unwrap(),
+            "a: lda #1 rts\nlda #1 rts\nb: lda #2 rts\nc = a\nd = b\n");
+    }
+
+    #[test]
+    fn it_should_not_alias_if_it_is_larger() {
+        let mut output = vec![word("a", "x"), word("b", "x")];
+        let report = Deduplicator::dedup(&mut output, &mut alias).unwrap();
+
+        assert_eq!(report, DedupReport::default());
+        assert_eq!(Compiled::flatten(output).unwrap(), "a: x\nb: x\n");
+    }
+}
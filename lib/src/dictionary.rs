@@ -27,6 +27,22 @@ impl Dictionary {
         self.words.extend(other.words.clone())
     }
 
+    /// every value that is not a word by its full name.
+    /// definitions of inner scopes hide the ones of their parents
+    pub fn constants(&self) -> HashMap<String, Object> {
+        let mut constants = match &self.parent {
+            Some(parent) => parent.constants(),
+            None => HashMap::new()
+        };
+        for (name, value) in &self.words {
+            match value {
+                Object::Callable(_) => constants.remove(name),
+                _ => constants.insert(name.clone(), value.clone())
+            };
+        }
+        constants
+    }
+
     pub fn get_full_name(name: &str, prefix: &Option<String>) -> String {
         match prefix {
             Some(prefix) =>
@@ -42,6 +58,11 @@ impl Dictionary {
         }
     }
 
+    /// the label of a word in the emitted code
+    pub fn get_label(full_name: &str) -> String {
+        full_name.replace("::", "__mod__")
+    }
+
     pub fn define(&mut self, name: &str, prefix: &Option<String>, value: &Object) {
         let full_name = Self::get_full_name(name, prefix);
         self.words.insert(full_name, value.clone());
@@ -148,4 +169,20 @@ mod tests {
         assert_eq!(env.get(&Token::new(TokenType::Word, Object::Nil, "name", 0, 0, ""), &None).unwrap(),
             Object::Number(100));
     }
+
+    #[test]
+    fn it_should_collect_constants() {
+        let mut parent = Dictionary::new();
+        parent.define("a", &None, &Object::Number(1));
+        parent.define("b", &None, &Object::Number(2));
+
+        let mut env = Dictionary::with(Some(Box::new(parent)));
+        env.define("b", &None, &Object::Callable(Box::new(super::super::builtins::Dup)));
+        env.define("c", &Some("module".into()), &Object::Number(3));
+
+        let constants = env.constants();
+        assert_eq!(constants.len(), 2);
+        assert_eq!(constants.get("a"), Some(&Object::Number(1)));
+        assert_eq!(constants.get("module::c"), Some(&Object::Number(3)));
+    }
 }
@@ -0,0 +1,321 @@
+use super::stmt::*;
+use super::expr::*;
+use super::error::*;
+use super::token::*;
+
+/// how a word changes the data stack.
+/// peak is the highest depth the word reaches
+/// counted from below its inputs
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StackEffect {
+    pub inputs: usize,
+    pub outputs: usize,
+    pub peak: usize
+}
+
+impl StackEffect {
+    pub fn new(inputs: usize, outputs: usize) -> Self {
+        Self::with(inputs, outputs, inputs.max(outputs))
+    }
+
+    pub fn with(inputs: usize, outputs: usize, peak: usize) -> Self {
+        Self {
+            inputs,
+            outputs,
+            peak
+        }
+    }
+
+    /// parses the text of a stack comment like ( a b -- c ).
+    /// None if it is a plain comment without --.
+    /// nested comments are not counted
+    pub fn from_comment(text: &str) -> Option<Self> {
+        let mut depth = 0;
+        let outer: String = text.chars()
+            .map(|c| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ if depth == 0 => return c,
+                    _ => {}
+                }
+                ' '
+            })
+            .collect();
+        let items: Vec<&str> = outer.split_whitespace().collect();
+        let split = items.iter().position(|item| *item == "--")?;
+        Some(Self::new(split, items.len() - split - 1))
+    }
+}
+
+/// looks up the effect of a word.
+/// None if the effect is not known
+pub type EffectLookup<'a> = &'a dyn Fn(&Token) -> Option<StackEffect>;
+
+/// the state of the data stack while walking through statements.
+/// depths are relative to the depth at the start
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StackState {
+    pub depth: isize,
+    pub lowest: isize,
+    pub peak: isize,
+    // false once a word without a known effect is used
+    pub known: bool,
+    // report an underflow when the depth drops below 0
+    pub strict: bool
+}
+
+impl StackState {
+    pub fn new(depth: usize, strict: bool) -> Self {
+        Self {
+            depth: depth as isize,
+            lowest: 0,
+            peak: depth as isize,
+            known: true,
+            strict
+        }
+    }
+
+    /// the effect of all statements checked so far
+    pub fn effect(&self) -> Option<StackEffect> {
+        if !self.known {
+            return None;
+        }
+        let inputs = -self.lowest;
+        Some(StackEffect::with(inputs as usize,
+            (self.depth + inputs) as usize,
+            (self.peak + inputs) as usize))
+    }
+
+    fn apply(&mut self, effect: StackEffect, token: &Token) -> BoxResult<()> {
+        let bottom = self.depth - effect.inputs as isize;
+        if self.strict && bottom < 0 {
+            return Err(Box::new(ExecError::new(ErrorType::StackUnderflow, token.clone())));
+        }
+
+        self.lowest = self.lowest.min(bottom);
+        self.peak = self.peak.max(bottom + effect.peak as isize);
+        self.depth = bottom + effect.outputs as isize;
+        Ok(())
+    }
+
+    /// combines the state after two branches
+    fn merge(&mut self, other: &StackState) {
+        self.lowest = self.lowest.min(other.lowest);
+        self.peak = self.peak.max(other.peak);
+        self.known &= other.known;
+    }
+
+    pub fn check_all(&mut self, stmts: &[Stmt], lookup: EffectLookup) -> BoxResult<()> {
+        for stmt in stmts {
+            self.check(stmt, lookup)?;
+        }
+        Ok(())
+    }
+
+    /// applies the effect of a statement.
+    /// stops as soon as the effect is unknown
+    pub fn check(&mut self, stmt: &Stmt, lookup: EffectLookup) -> BoxResult<()> {
+        if !self.known {
+            return Ok(());
+        }
+
+        match stmt {
+            Stmt::Expr(expr) => {
+                let effect = match &expr.expr {
+                    Expr::Word(word) => lookup(&word.name),
+                    _ => Some(StackEffect::new(0, 1))
+                };
+                match effect {
+                    Some(effect) => self.apply(effect, &stmt.token())?,
+                    None => self.known = false
+                }
+            },
+            Stmt::Tick(_) => self.apply(StackEffect::new(0, 1), &stmt.token())?,
+            Stmt::Block(block) => self.check_all(&block.body, lookup)?,
+            Stmt::If(if_stmt) => {
+                self.apply(StackEffect::new(1, 0), &stmt.token())?;
+
+                let mut else_state = *self;
+                self.check(&if_stmt.then_block, lookup)?;
+                if let Some(else_block) = &if_stmt.else_block {
+                    else_state.check(else_block, lookup)?;
+                }
+
+                if self.known && else_state.known && self.depth != else_state.depth {
+                    return Err(Box::new(ExecError::new(ErrorType::UnbalancedBranches, stmt.token())));
+                }
+                self.merge(&else_state);
+            },
+            Stmt::Loop(loop_stmt) => {
+                // the body has to leave exactly one flag for until
+                let depth = self.depth;
+                self.check(&loop_stmt.block, lookup)?;
+                if self.known && self.depth != depth + 1 {
+                    return Err(Box::new(ExecError::new(ErrorType::UnbalancedLoop, stmt.token())));
+                }
+                self.apply(StackEffect::new(1, 0), &stmt.token())?;
+            },
+            Stmt::While(while_stmt) => {
+                // the condition leaves one flag, the block keeps the depth
+                let depth = self.depth;
+                self.check(&while_stmt.condition, lookup)?;
+                if self.known && self.depth != depth + 1 {
+                    return Err(Box::new(ExecError::new(ErrorType::UnbalancedLoop, stmt.token())));
+                }
+                self.apply(StackEffect::new(1, 0), &stmt.token())?;
+                self.check(&while_stmt.block, lookup)?;
+                if self.known && self.depth != depth {
+                    return Err(Box::new(ExecError::new(ErrorType::UnbalancedLoop, stmt.token())));
+                }
+            },
+            Stmt::Case(case_stmt) => {
+                // every value pushes one item that of compares with the selector.
+                // all branches and the default have to end at the same depth
+                let mut paths = vec![];
+                for branch in &case_stmt.branches {
+                    let mut state = *self;
+                    state.check(&branch.value, lookup)?;
+                    if state.known && state.depth != self.depth + 1 {
+                        return Err(Box::new(ExecError::new(ErrorType::UnbalancedBranches, branch.token.clone())));
+                    }
+                    state.apply(StackEffect::new(2, 0), &branch.token)?;
+                    state.check(&branch.block, lookup)?;
+                    paths.push(state);
+                }
+
+                let mut default_state = *self;
+                default_state.check(&case_stmt.default, lookup)?;
+                default_state.apply(StackEffect::new(1, 0), &stmt.token())?;
+
+                for state in paths {
+                    if state.known && default_state.known && state.depth != default_state.depth {
+                        return Err(Box::new(ExecError::new(ErrorType::UnbalancedBranches, stmt.token())));
+                    }
+                    default_state.merge(&state);
+                }
+                *self = default_state;
+            },
+            Stmt::Do(do_stmt) => {
+                // the body has to keep the depth, +loop takes one step from it
+                self.apply(StackEffect::new(2, 0), &stmt.token())?;
+                let depth = self.depth;
+                self.check(&do_stmt.block, lookup)?;
+                let expected = if do_stmt.plus { depth + 1 } else { depth };
+                if self.known && self.depth != expected {
+                    return Err(Box::new(ExecError::new(ErrorType::UnbalancedLoop, stmt.token())));
+                }
+                if do_stmt.plus {
+                    self.apply(StackEffect::new(1, 0), &stmt.token())?;
+                }
+            },
+            Stmt::Index(_) => self.apply(StackEffect::new(0, 1), &stmt.token())?,
+            Stmt::Expand(expand) => self.check_all(&expand.body, lookup)?,
+            // the depth at an early exit is not compared with the end
+            Stmt::Exit(_) => self.known = false,
+            // raw code may do anything
+            Stmt::Asm(_) => self.known = false,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::parser::Parser;
+
+    fn lookup(token: &Token) -> Option<StackEffect> {
+        match token.lexeme.as_ref() {
+            "+" => Some(StackEffect::new(2, 1)),
+            "dup" => Some(StackEffect::new(1, 2)),
+            "drop" => Some(StackEffect::new(1, 0)),
+            _ => None
+        }
+    }
+
+    fn check(source: &str, strict: bool) -> BoxResult<StackState> {
+        let stmts = Parser::new(source, "").unwrap().parse().unwrap();
+        let mut state = StackState::new(0, strict);
+        state.check_all(&stmts, &lookup)?;
+        Ok(state)
+    }
+
+    fn error_type(result: BoxResult<StackState>) -> ErrorType {
+        result.unwrap_err().downcast_ref::<ExecError>().unwrap().error_type
+    }
+
+    #[test]
+    fn it_should_compute_effects() {
+        let state = check("+ dup 1 2", false).unwrap();
+        assert_eq!(state.effect(), Some(StackEffect::with(2, 4, 4)));
+
+        let state = check("1 2 3 + + drop", true).unwrap();
+        assert_eq!(state.effect(), Some(StackEffect::with(0, 0, 3)));
+    }
+
+    #[test]
+    fn it_should_parse_stack_comments() {
+        assert_eq!(StackEffect::from_comment(" a b -- c "), Some(StackEffect::new(2, 1)));
+        assert_eq!(StackEffect::from_comment("--"), Some(StackEffect::new(0, 0)));
+        assert_eq!(StackEffect::from_comment(" n -- n n "), Some(StackEffect::new(1, 2)));
+        assert_eq!(StackEffect::from_comment(" a ( ignored -- ) b -- "), Some(StackEffect::new(2, 0)));
+        assert_eq!(StackEffect::from_comment(" just a comment "), None);
+    }
+
+    #[test]
+    fn it_should_report_underflow() {
+        assert_eq!(error_type(check("1 +", true)), ErrorType::StackUnderflow);
+    }
+
+    #[test]
+    fn it_should_report_unbalanced_branches() {
+        assert_eq!(error_type(check("1 if 2 then", false)), ErrorType::UnbalancedBranches);
+        assert_eq!(error_type(check("1 if 2 else 3 3 then", false)), ErrorType::UnbalancedBranches);
+
+        let state = check("1 1 if 2 + else 3 + then", true).unwrap();
+        assert_eq!(state.effect(), Some(StackEffect::with(0, 1, 2)));
+    }
+
+    #[test]
+    fn it_should_report_unbalanced_loops() {
+        assert_eq!(error_type(check("loop 1 1 until", false)), ErrorType::UnbalancedLoop);
+
+        let state = check("loop 1 until", true).unwrap();
+        assert_eq!(state.effect(), Some(StackEffect::with(0, 0, 1)));
+    }
+
+    #[test]
+    fn it_should_report_unbalanced_do_loops() {
+        assert_eq!(error_type(check("10 0 do i loop", false)), ErrorType::UnbalancedLoop);
+        assert_eq!(error_type(check("10 0 do 1 drop +loop", false)), ErrorType::UnbalancedLoop);
+
+        let state = check("1 10 0 do i + loop 10 0 do 2 +loop", true).unwrap();
+        assert_eq!(state.effect(), Some(StackEffect::with(0, 1, 3)));
+    }
+
+    #[test]
+    fn it_should_report_unbalanced_while_loops() {
+        assert_eq!(error_type(check("begin 1 1 while repeat", false)), ErrorType::UnbalancedLoop);
+        assert_eq!(error_type(check("begin 1 while 1 repeat", false)), ErrorType::UnbalancedLoop);
+
+        let state = check("1 begin dup while 1 + repeat", true).unwrap();
+        assert_eq!(state.effect(), Some(StackEffect::with(0, 1, 2)));
+    }
+
+    #[test]
+    fn it_should_report_unbalanced_cases() {
+        assert_eq!(error_type(check("1 case 1 of 2 endof endcase", false)), ErrorType::UnbalancedBranches);
+        assert_eq!(error_type(check("1 case 1 1 of endof endcase", false)), ErrorType::UnbalancedBranches);
+
+        let state = check("1 case 1 of 2 endof 3 of 4 endof dup endcase", true).unwrap();
+        assert_eq!(state.effect(), Some(StackEffect::with(0, 1, 2)));
+    }
+
+    #[test]
+    fn it_should_stop_at_unknown_words() {
+        let state = check("1 unknown + + +", true).unwrap();
+        assert_eq!(state.effect(), None);
+    }
+}
@@ -8,6 +8,8 @@ pub type BoxResult<T> = Result<T, Box<dyn std::error::Error>>;
 pub enum ErrorType {
     InvalidToken,
     UnterminatedString,
+    UnterminatedComment,
+    ParenGrouping,
     BadNumber,
     NumberParseError,
     UnexpectedToken,
@@ -19,6 +21,15 @@ pub enum ErrorType {
     TypeError,
     DivisionByZero,
     InvalidString,
+    UnbalancedBranches,
+    UnbalancedLoop,
+    StackEffectMismatch,
+    IndexOutsideLoop,
+    RecurseOutsideWord,
+    UndefinedForward,
+    InvalidForward,
+    InvalidExit,
+    InvalidPeephole,
     IOError
 }
 
@@ -46,6 +57,8 @@ impl ExecError {
         match self.error_type {
             ErrorType::InvalidToken => "Bad token",
             ErrorType::UnterminatedString => "Unterminated string",
+            ErrorType::UnterminatedComment => "Unterminated comment",
+            ErrorType::ParenGrouping => "Parentheses start a comment and can not group values. Add a space after ( or remove the parentheses",
             ErrorType::BadNumber => "Bad number",
             ErrorType::NumberParseError => "Number parser error",
             ErrorType::UnexpectedToken => "Unexpected token",
@@ -57,6 +70,15 @@ impl ExecError {
             ErrorType::TypeError => "Type error",
             ErrorType::DivisionByZero => "Division by 0",
             ErrorType::InvalidString => "Invalid string",
+            ErrorType::UnbalancedBranches => "Branches leave different stack depths",
+            ErrorType::UnbalancedLoop => "Loop body changes the stack depth",
+            ErrorType::StackEffectMismatch => "Body does not match the declared stack effect",
+            ErrorType::IndexOutsideLoop => "i can only be used inside of a do loop",
+            ErrorType::RecurseOutsideWord => "recurse can only be used inside of a regular word",
+            ErrorType::UndefinedForward => "Forward declared word is never defined",
+            ErrorType::InvalidForward => "Forward declared word has to be a regular word",
+            ErrorType::InvalidExit => "exit can only be used inside of a regular word or a constant and not inside of a do loop",
+            ErrorType::InvalidPeephole => "Peephole rules can not use templates with __ARG__ or __LINE__",
             ErrorType::IOError => "IO Error"
         }
     }
@@ -70,7 +92,12 @@ impl std::error::Error for ExecError {
 
 impl fmt::Display for ExecError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} in {}:{} ({})", self.to_string(), self.token.path, self.token.line, self.token.lexeme)
+        // generated tokens do not have a column
+        match self.token.span.column {
+            0 => write!(f, "{} in {}:{} ({})", self.to_string(), self.token.path, self.token.line, self.token.lexeme),
+            column => write!(f, "{} in {}:{}:{} ({})", self.to_string(), self.token.path, self.token.line, column,
+                self.token.lexeme)
+        }
     }
 }
 
@@ -85,9 +112,18 @@ pub struct ErrorList {
 }
 
 impl ErrorList {
+    /// errors of imported files are part of the list itself
     pub fn new(errors: Vec<Box<dyn std::error::Error>>) -> Self {
+        let mut flat = vec![];
+        for err in errors {
+            match err.downcast::<ErrorList>() {
+                Ok(list) => flat.extend(list.errors),
+                Err(err) => flat.push(err)
+            }
+        }
+
         Self {
-            errors
+            errors: flat
         }
     }
 }
@@ -102,7 +138,7 @@ impl fmt::Display for ErrorList {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut output = "".to_string();
         for err in &self.errors[..] {
-            output = format!("{}\n", err);
+            output.push_str(&format!("{}\n", err));
         }
         write!(f, "{}", output)
     }
@@ -113,3 +149,19 @@ impl fmt::Debug for ErrorList {
         write!(f, "Error list {:?}", self.errors)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::token::TokenType;
+    use super::super::object::Object;
+
+    #[test]
+    fn it_should_display_every_error() {
+        let errors = ErrorList::new(vec![
+            Box::new(ExecError::new(ErrorType::UndefinedWord, Token::new(TokenType::Word, Object::Nil, "foo", 1, 0, "a.fth"))),
+            Box::new(ExecError::new(ErrorType::ExpectedName, Token::new(TokenType::Word, Object::Nil, ":", 3, 0, "a.fth")))
+        ]);
+        assert_eq!(format!("{}", errors), "Undefined word in a.fth:1 (foo)\nExpected name in a.fth:3 (:)\n");
+    }
+}
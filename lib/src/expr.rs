@@ -27,6 +27,14 @@ impl ExprNode for Expr {
             Self::Unary(unary) => unary.op.clone()
         }
     }
+
+    fn span(&self) -> Span {
+        match self {
+            Self::Literal(literal) => literal.span(),
+            Self::Word(word) => word.span(),
+            Self::Unary(unary) => unary.span()
+        }
+    }
 }
 
 pub trait ExprNode {
@@ -34,6 +42,11 @@ pub trait ExprNode {
     fn token(&self) -> Token {
         Token::new(TokenType::Invalid, Object::Nil, "", 0, 0, "")
     }
+
+    /// the source covered by the whole expression
+    fn span(&self) -> Span {
+        self.token().span
+    }
 }
 
 pub trait ExprVisitor {
@@ -59,6 +72,10 @@ impl ExprNode for LiteralExpr {
     fn accept(&mut self, visitor: &mut dyn ExprVisitor) -> BoxResult<Object> {
         return visitor.visit_literal(self);
     }
+
+    fn span(&self) -> Span {
+        self.literal.span
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -78,6 +95,10 @@ impl ExprNode for WordExpr {
     fn accept(&mut self, visitor: &mut dyn ExprVisitor) -> BoxResult<Object> {
         return visitor.visit_word(self);
     }
+
+    fn span(&self) -> Span {
+        self.name.span
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -99,4 +120,8 @@ impl ExprNode for UnaryExpr {
     fn accept(&mut self, visitor: &mut dyn ExprVisitor) -> BoxResult<Object> {
         return visitor.visit_unary(self);
     }
+
+    fn span(&self) -> Span {
+        self.op.span.merge(&self.right.span())
+    }
 }
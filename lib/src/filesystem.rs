@@ -1,7 +1,12 @@
 use std::fs;
+use std::str;
 use super::error::*;
+use super::token::{Token, TokenType, Span};
+use super::object::Object;
 use std::path::{Path, PathBuf};
 use std::env;
+#[cfg(test)]
+use std::collections::HashMap;
 
 pub trait FileSystemManager {
     fn read_file(&self, path: &str) -> BoxResult<String>;
@@ -13,7 +18,7 @@ pub struct LocalFileSystem;
 
 impl FileSystemManager for LocalFileSystem {
     fn read_file(&self, path: &str) -> BoxResult<String> {
-        Ok(fs::read_to_string(path)?)
+        decode(&fs::read(path)?, path)
     }
 
     fn set_current_dir(&self, path: &str) -> bool {
@@ -25,3 +30,67 @@ impl FileSystemManager for LocalFileSystem {
         Ok(env::current_dir()?)
     }
 }
+
+/// source files have to be valid utf-8.
+/// the error points at the first invalid byte
+pub fn decode(bytes: &[u8], path: &str) -> BoxResult<String> {
+    let err = match str::from_utf8(bytes) {
+        Ok(source) => return Ok(source.to_string()),
+        Err(err) => err
+    };
+
+    let offset = err.valid_up_to();
+    let valid = str::from_utf8(&bytes[..offset])?;
+    let line = valid.matches('\n').count() + 1;
+    let column = valid.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+
+    let token = Token::new(TokenType::Invalid, Object::Nil, &format!("byte {}", offset), line, offset, path)
+        .with_span(Span::new(line, column, line, column));
+    Err(Box::new(ExecError::new(ErrorType::InvalidToken, token)))
+}
+
+/// files kept in memory for tests
+#[cfg(test)]
+pub struct MemoryFileSystem {
+    files: HashMap<String, String>
+}
+
+#[cfg(test)]
+impl MemoryFileSystem {
+    pub fn with(files: Vec<(&str, &str)>) -> Self {
+        Self {
+            files: files.into_iter().map(|(path, source)| (path.to_string(), source.to_string())).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+impl FileSystemManager for MemoryFileSystem {
+    fn read_file(&self, path: &str) -> BoxResult<String> {
+        match self.files.get(path) {
+            Some(source) => Ok(source.clone()),
+            None => Err(Box::new(std::io::Error::from(std::io::ErrorKind::NotFound)))
+        }
+    }
+
+    fn set_current_dir(&self, _path: &str) -> bool {
+        true
+    }
+
+    fn current_dir(&self) -> BoxResult<PathBuf> {
+        Ok(PathBuf::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_report_the_offset_of_invalid_utf8() {
+        assert_eq!(decode("größe \u{FFFD}".as_bytes(), "main.fth").unwrap(), "größe \u{FFFD}");
+
+        let err = decode(b"word\n  \"a\xfe\"", "main.fth").unwrap_err();
+        assert_eq!(err.to_string(), "Bad token in main.fth:2:5 (byte 9)");
+    }
+}
@@ -21,6 +21,15 @@ pub struct Interpreter {
 
     mod_name: Option<String>,
 
+    // index of every do loop that is running
+    indices: Vec<ObjNumber>,
+
+    // statements built by the emit_* words
+    pub emitted: Vec<Stmt>,
+
+    // set by exit until the word returns
+    pub exiting: bool,
+
     halt: bool
 }
 
@@ -41,6 +50,14 @@ impl Interpreter {
 
         builtins.define("drop", &None, &Object::Callable(Box::new(DropTop)));
         builtins.define("dup", &None, &Object::Callable(Box::new(Dup)));
+        builtins.define("swap", &None, &Object::Callable(Box::new(Swap)));
+        builtins.define("concat", &None, &Object::Callable(Box::new(Concat)));
+
+        // used by :parsing words to build statements
+        builtins.define("emit_asm", &None, &Object::Callable(Box::new(EmitAsm)));
+        builtins.define("emit_word", &None, &Object::Callable(Box::new(EmitWord)));
+        builtins.define("emit_literal", &None, &Object::Callable(Box::new(EmitLiteral)));
+        builtins.define("emit_const", &None, &Object::Callable(Box::new(EmitConst)));
 
         builtins
     }
@@ -53,6 +70,9 @@ impl Interpreter {
             dictionary: Self::builtins(),
             stack: vec![],
             mod_name: None,
+            indices: vec![],
+            emitted: vec![],
+            exiting: false,
             halt: false
         })
     }
@@ -63,10 +83,36 @@ impl Interpreter {
             dictionary: Self::builtins(),
             stack: vec![],
             mod_name: None,
+            indices: vec![],
+            emitted: vec![],
+            exiting: false,
             halt: false
         }
     }
 
+    /// an interpreter that knows the constants of a compiler.
+    /// constants of the current module can also be used without their module name
+    pub fn with_constants(stmts: Vec<Stmt>, dictionary: &Dictionary, mod_name: &Option<String>) -> Self {
+        let mut interpreter = Self::with(stmts);
+        let constants = dictionary.constants();
+        for (name, value) in &constants {
+            interpreter.dictionary.define(name, &None, value);
+        }
+
+        if let Some(mod_name) = mod_name {
+            let prefix = format!("{}::", mod_name);
+            for (name, value) in &constants {
+                match name.strip_prefix(&prefix) {
+                    Some(short) if !constants.contains_key(short) => {
+                        interpreter.dictionary.define(short, &None, value);
+                    },
+                    _ => {}
+                }
+            }
+        }
+        interpreter
+    }
+
     pub fn pop(&mut self, token: &Token) -> BoxResult<Object> {
         match self.stack.pop() {
             Some(obj) => Ok(obj),
@@ -146,6 +192,9 @@ impl StmtVisitor for Interpreter {
 
         for stmt in &mut block.body {
             self.execute(stmt)?;
+            if self.exiting {
+                break;
+            }
         }
 
         // move env back
@@ -163,6 +212,45 @@ impl StmtVisitor for Interpreter {
         Ok(Compiled::new(vec![]))
     }
 
+    fn visit_let(&mut self, _stmt: &mut LetStmt) -> BoxResult<Compiled> {
+        // variables only exist in compiled code
+        Ok(Compiled::new(vec![]))
+    }
+
+    fn visit_forward(&mut self, _stmt: &mut ForwardStmt) -> BoxResult<Compiled> {
+        // words are looked up when they are called
+        Ok(Compiled::new(vec![]))
+    }
+
+    fn visit_cond(&mut self, stmt: &mut CondStmt) -> BoxResult<Compiled> {
+        let taken = match &mut stmt.condition {
+            Condition::Defined(name) => self.dictionary.get(name, &self.mod_name).is_ok(),
+            Condition::Expr(condition) => {
+                self.execute(condition)?;
+                self.pop(&stmt.token)?.truthy()
+            }
+        };
+
+        let branch = if taken { &mut stmt.then_branch } else { &mut stmt.else_branch };
+        for stmt in branch {
+            self.execute(stmt)?;
+            if self.exiting {
+                break;
+            }
+        }
+        Ok(Compiled::new(vec![]))
+    }
+
+    fn visit_expand(&mut self, stmt: &mut ExpandStmt) -> BoxResult<Compiled> {
+        for stmt in &mut stmt.body {
+            self.execute(stmt)?;
+            if self.exiting {
+                break;
+            }
+        }
+        Ok(Compiled::new(vec![]))
+    }
+
     fn visit_if(&mut self, stmt: &mut IfStmt) -> BoxResult<Compiled> {
         // if simply checks top of stack
         let value = self.pop(&stmt.token())?;
@@ -181,13 +269,101 @@ impl StmtVisitor for Interpreter {
 
     fn visit_loop(&mut self, stmt: &mut LoopStmt) -> BoxResult<Compiled> {
         // while top of stack is truthy loop
-        while self.peek(&stmt.token())?.truthy() {
+        while !self.exiting && self.peek(&stmt.token())?.truthy() {
             self.execute(&mut stmt.block)?;
         }
 
         Ok(Compiled::new(vec![]))
     }
 
+    fn visit_while(&mut self, stmt: &mut WhileStmt) -> BoxResult<Compiled> {
+        // the condition leaves a flag that is checked before every run
+        loop {
+            self.execute(&mut stmt.condition)?;
+            if self.exiting || !self.pop(&stmt.token())?.truthy() {
+                break;
+            }
+            self.execute(&mut stmt.block)?;
+            if self.exiting {
+                break;
+            }
+        }
+
+        Ok(Compiled::new(vec![]))
+    }
+
+    fn visit_case(&mut self, stmt: &mut CaseStmt) -> BoxResult<Compiled> {
+        let token = stmt.token();
+        let selector = self.pop(&token)?;
+
+        for branch in &mut stmt.branches {
+            self.execute(&mut branch.value)?;
+            if self.pop(&branch.token)? == selector {
+                return self.execute(&mut branch.block);
+            }
+        }
+
+        // the default sees the selector, endcase drops it
+        self.push(selector);
+        self.execute(&mut stmt.default)?;
+        if !self.exiting {
+            self.pop(&token)?;
+        }
+        Ok(Compiled::new(vec![]))
+    }
+
+    fn visit_do(&mut self, stmt: &mut DoStmt) -> BoxResult<Compiled> {
+        let token = stmt.token();
+        let start = self.pop(&token)?;
+        let limit = self.pop(&token)?;
+        let (mut index, limit) = match (start, limit) {
+            (Object::Number(start), Object::Number(limit)) => (start, limit),
+            _ => return Err(Box::new(ExecError::new(ErrorType::TypeError, token)))
+        };
+
+        // the body runs at least once.
+        // counting down includes the limit just like in other forths
+        loop {
+            self.indices.push(index);
+            let result = self.execute(&mut stmt.block);
+            self.indices.pop();
+            result?;
+
+            let step = if stmt.plus {
+                match self.pop(&token)? {
+                    Object::Number(step) => step,
+                    _ => return Err(Box::new(ExecError::new(ErrorType::TypeError, token)))
+                }
+            } else {
+                1
+            };
+
+            index += step;
+            if (step >= 0 && index >= limit) || (step < 0 && index < limit) {
+                break;
+            }
+        }
+
+        Ok(Compiled::new(vec![]))
+    }
+
+    fn visit_index(&mut self, stmt: &mut IndexStmt) -> BoxResult<Compiled> {
+        match self.indices.last() {
+            Some(index) => {
+                let index = *index;
+                self.push(Object::Number(index));
+                Ok(Compiled::new(vec![]))
+            },
+            _ => Err(Box::new(ExecError::new(ErrorType::IndexOutsideLoop, stmt.token())))
+        }
+    }
+
+    fn visit_exit(&mut self, _stmt: &mut ExitStmt) -> BoxResult<Compiled> {
+        // every block stops until the word returns
+        self.exiting = true;
+        Ok(Compiled::new(vec![]))
+    }
+
     fn visit_impoprt(&mut self, _stmt: &mut ImportStmt) -> BoxResult<Compiled> {
         Ok(Compiled::new(vec![]))
     }
@@ -210,6 +386,10 @@ impl StmtVisitor for Interpreter {
         // TODO interpreter needs to support tick
         Ok(Compiled::new(vec![]))
     }
+
+    fn visit_peephole(&mut self, _stmt: &mut PeepholeStmt) -> BoxResult<Compiled> {
+        Ok(Compiled::new(vec![]))
+    }
 }
 
 impl ExprVisitor for Interpreter {
@@ -321,6 +501,54 @@ mod tests {
         assert_eq!(interpreter.stack.pop(), Some(Object::Number(0)));
     }
 
+    #[test]
+    fn it_should_count_with_do() {
+        let mut interpreter = Interpreter::new("0 5 0 do i + loop", "").unwrap();
+        let _ = interpreter.interprete().unwrap();
+        assert_eq!(interpreter.stack, vec![Object::Number(10)]);
+
+        let mut interpreter = Interpreter::new("0 10 0 do i + 2 +loop", "").unwrap();
+        let _ = interpreter.interprete().unwrap();
+        assert_eq!(interpreter.stack, vec![Object::Number(20)]);
+
+        // counting down includes the limit
+        let mut interpreter = Interpreter::new("0 0 3 do i + -1 +loop", "").unwrap();
+        let _ = interpreter.interprete().unwrap();
+        assert_eq!(interpreter.stack, vec![Object::Number(6)]);
+    }
+
+    #[test]
+    fn it_should_nest_do_loops() {
+        let mut interpreter = Interpreter::new("0 3 0 do 2 0 do i + loop i + loop", "").unwrap();
+        let _ = interpreter.interprete().unwrap();
+        assert_eq!(interpreter.stack, vec![Object::Number(6)]);
+    }
+
+    #[test]
+    fn it_should_check_before_repeating() {
+        let mut interpreter = Interpreter::new("0 5 begin dup while swap 1 + swap 1 - repeat drop", "").unwrap();
+        let _ = interpreter.interprete().unwrap();
+        assert_eq!(interpreter.stack, vec![Object::Number(5)]);
+
+        let mut interpreter = Interpreter::new("1 begin 0 while 2 repeat", "").unwrap();
+        let _ = interpreter.interprete().unwrap();
+        assert_eq!(interpreter.stack, vec![Object::Number(1)]);
+    }
+
+    #[test]
+    fn it_should_select_case() {
+        let source = ": select case 1 of 10 endof 1 1 + of 20 endof 30 + dup endcase ;";
+
+        let mut interpreter = Interpreter::new(&format!("{} 2 select", source), "").unwrap();
+        let _ = interpreter.interprete().unwrap();
+        assert_eq!(interpreter.stack, vec![Object::Number(20)]);
+
+        // the default sees the selector, endcase drops the top
+        let mut interpreter = Interpreter::new(&format!("{} 5 select", source), "").unwrap();
+        let _ = interpreter.interprete().unwrap();
+        assert_eq!(interpreter.stack, vec![Object::Number(35)]);
+    }
+
     #[test]
     fn it_should_call_add_and_typeerror() {
         let mut interpreter = Interpreter::new("\"Hi\" 1 +", "").unwrap();
@@ -356,4 +584,31 @@ mod tests {
         let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
         assert_eq!(errors_id, vec!["type: DivisionByZero; lexeme: /".to_string()]);
     }
+
+    #[test]
+    fn it_should_exit_early() {
+        let mut interpreter = Interpreter::new(": first if 1 exit then 2 ; 1 first 0 first", "").unwrap();
+        let _ = interpreter.interprete().unwrap();
+        assert_eq!(interpreter.stack, vec![Object::Number(1), Object::Number(2)]);
+
+        // exit leaves every loop of the word, but not the caller
+        let mut interpreter = Interpreter::new(
+            ": up begin 1 while 1 + dup 3 - if else exit then repeat ; 0 up 7", "").unwrap();
+        let _ = interpreter.interprete().unwrap();
+        assert_eq!(interpreter.stack, vec![Object::Number(3), Object::Number(7)]);
+    }
+
+    #[test]
+    fn it_should_interprete_conditional_compilation() {
+        let mut interpreter = Interpreter::new(":c NES 0 ; :if NES ; 1 :else 2 :endif :ifdef NES 3 :else 4 :endif", "").unwrap();
+        let _ = interpreter.interprete().unwrap();
+        assert_eq!(interpreter.stack, vec![Object::Number(2), Object::Number(3)]);
+    }
+
+    #[test]
+    fn it_should_interprete_macros() {
+        let mut interpreter = Interpreter::new(":macro twice body ; body body ; 1 twice [ 2 + ]", "").unwrap();
+        let _ = interpreter.interprete().unwrap();
+        assert_eq!(interpreter.stack, vec![Object::Number(5)]);
+    }
 }
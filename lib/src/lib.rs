@@ -12,3 +12,9 @@ pub mod interpreter;
 pub mod builtins;
 pub mod filesystem;
 pub mod optimizer;
+pub mod peephole;
+pub mod treeshake;
+pub mod effect;
+pub mod outline;
+pub mod dedup;
+pub mod macros;
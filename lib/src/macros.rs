@@ -0,0 +1,141 @@
+use super::stmt::*;
+use super::expr::*;
+use super::token::*;
+use std::collections::HashMap;
+
+/// a word declared with :macro.
+/// every use site gets a copy of the body with the parameters
+/// replaced by the statements passed to it
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub params: Vec<Token>,
+    pub body: Vec<Stmt>
+}
+
+impl Macro {
+    /// the body with every parameter replaced by its argument
+    pub fn expand(&self, token: Token, args: Vec<Vec<Stmt>>) -> ExpandStmt {
+        let args: HashMap<String, Vec<Stmt>> = self.params.iter()
+            .map(|param| param.lexeme.clone())
+            .zip(args)
+            .collect();
+
+        let mut body = self.body.clone();
+        Self::substitute(&mut body, &args);
+        ExpandStmt::new(body, token)
+    }
+
+    fn substitute(stmts: &mut Vec<Stmt>, args: &HashMap<String, Vec<Stmt>>) {
+        let mut result = vec![];
+
+        for mut stmt in stmts.drain(..) {
+            if let Stmt::Expr(ExprStmt { expr: Expr::Word(word) }) = &stmt {
+                if let Some(arg) = args.get(&word.name.lexeme) {
+                    result.extend(arg.iter().cloned());
+                    continue;
+                }
+            }
+            Self::substitute_in(&mut stmt, args);
+            result.push(stmt);
+        }
+
+        *stmts = result;
+    }
+
+    fn substitute_in(stmt: &mut Stmt, args: &HashMap<String, Vec<Stmt>>) {
+        match stmt {
+            Stmt::Expr(expr) => Self::substitute_expr(&mut expr.expr, args),
+            Stmt::Tick(tick) => Self::substitute_expr(&mut tick.word, args),
+            Stmt::Block(block) => Self::substitute(&mut block.body, args),
+            Stmt::If(if_stmt) => {
+                Self::substitute_in(&mut if_stmt.then_block, args);
+                if let Some(else_block) = &mut if_stmt.else_block {
+                    Self::substitute_in(else_block, args);
+                }
+            },
+            Stmt::Loop(loop_stmt) => Self::substitute_in(&mut loop_stmt.block, args),
+            Stmt::While(while_stmt) => {
+                Self::substitute_in(&mut while_stmt.condition, args);
+                Self::substitute_in(&mut while_stmt.block, args);
+            },
+            Stmt::Case(case_stmt) => {
+                for branch in &mut case_stmt.branches {
+                    Self::substitute_in(&mut branch.value, args);
+                    Self::substitute_in(&mut branch.block, args);
+                }
+                Self::substitute_in(&mut case_stmt.default, args);
+            },
+            Stmt::Do(do_stmt) => Self::substitute_in(&mut do_stmt.block, args),
+            Stmt::Cond(cond) => {
+                if let Condition::Expr(condition) = &mut cond.condition {
+                    Self::substitute_in(condition, args);
+                }
+                Self::substitute(&mut cond.then_branch, args);
+                Self::substitute(&mut cond.else_branch, args);
+            },
+            Stmt::Expand(expand) => Self::substitute(&mut expand.body, args),
+            _ => {}
+        }
+    }
+
+    /// parameters inside of expressions can only be replaced
+    /// by a single word or literal
+    fn substitute_expr(expr: &mut Expr, args: &HashMap<String, Vec<Stmt>>) {
+        match expr {
+            Expr::Word(word) => {
+                if let Some([Stmt::Expr(arg)]) = args.get(&word.name.lexeme).map(|arg| arg.as_slice()) {
+                    *expr = arg.expr.clone();
+                }
+            },
+            Expr::Unary(unary) => Self::substitute_expr(&mut unary.right, args),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::parser::Parser;
+    use super::super::object::Object;
+
+    fn word(lexeme: &str) -> Token {
+        Token::new(TokenType::Word, Object::Nil, lexeme, 1, 0, "")
+    }
+
+    fn lexemes(stmts: &[Stmt]) -> Vec<String> {
+        stmts.iter().map(|stmt| stmt.token().lexeme.trim().to_string()).collect()
+    }
+
+    #[test]
+    fn it_should_substitute_arguments() {
+        let stmts = Parser::new("swap n dup if n then ' n :i8 n", "").unwrap().parse().unwrap();
+        let args = Parser::new("1 +", "").unwrap().parse().unwrap();
+        let value = Parser::new("7", "").unwrap().parse().unwrap();
+        let times = Macro {
+            params: vec![word("n")],
+            body: stmts
+        };
+
+        let expand = times.expand(word("m"), vec![args]);
+        assert_eq!(lexemes(&expand.body), vec!["swap", "1", "+", "dup", "if", "'", ":i8"]);
+        match &expand.body[4] {
+            Stmt::If(if_stmt) => match &*if_stmt.then_block {
+                Stmt::Block(block) => assert_eq!(lexemes(&block.body), vec!["1", "+"]),
+                _ => panic!("Expected block")
+            },
+            _ => panic!("Expected if")
+        }
+
+        // a single word or literal also replaces parameters inside of expressions
+        let expand = times.expand(word("m"), vec![value]);
+        match &expand.body[4] {
+            Stmt::Tick(tick) => assert_eq!(tick.word.token().lexeme, "7"),
+            _ => panic!("Expected tick")
+        }
+        match &expand.body[5] {
+            Stmt::Expr(ExprStmt { expr: Expr::Unary(unary) }) => assert_eq!(unary.right.token().lexeme, "7"),
+            _ => panic!("Expected unary")
+        }
+    }
+}
@@ -1,12 +1,576 @@
 use super::stmt::*;
+use super::expr::*;
+use super::object::*;
+use super::token::*;
+use super::interpreter::Interpreter;
+use super::dictionary::Dictionary;
+use std::collections::HashSet;
+
+pub type PassFn = fn(&Optimizer, &mut Vec<Stmt>);
+
+/// a named optimization step
+#[derive(Clone)]
+pub struct Pass {
+    pub name: String,
+    // lowest optimization level that enables the pass
+    pub level: usize,
+    // None for passes the compiler applies during code generation
+    pub run: Option<PassFn>
+}
 
 /// the optimizer takes the parsed AST and attempts
 /// to apply some steps to improve code speed
 /// and reduce code size
+#[derive(Clone)]
+pub struct Optimizer {
+    pub level: usize,
+    passes: Vec<Pass>,
+    // passes that were explicitly turned on or off
+    enabled: HashSet<String>,
+    disabled: HashSet<String>,
 
-pub struct Optimizer;
+    // words defined by imported modules.
+    // they are never folded, just like words defined in the file
+    pub imported_words: HashSet<String>,
+
+    // print the AST after every pass
+    pub dump: bool
+}
+
+/// words that can be evaluated at compile time
+/// if all their inputs are literals
+/// (word, number of inputs)
+const FOLDABLE: [(&str, usize); 9] = [
+    ("+", 2), ("-", 2), ("*", 2), ("/", 2), ("%", 2),
+    ("&", 2), ("|", 2), ("^", 2), ("~", 1)
+];
 
 impl Optimizer {
-    pub fn optimize(_stmts: &mut Vec<Stmt>) {
+    /// passes of this level are only enabled when optimizing for size
+    pub const SIZE: usize = usize::MAX;
+
+    pub fn new(level: usize) -> Self {
+        let mut optimizer = Self {
+            level,
+            passes: vec![],
+            enabled: HashSet::new(),
+            disabled: HashSet::new(),
+            imported_words: HashSet::new(),
+            dump: false
+        };
+
+        optimizer.register("fold", 1, Some(Self::constant_folding));
+        optimizer.register("dead-branch", 1, Some(Self::dead_branches));
+        optimizer.register("tailcall", 1, None);
+        optimizer.register("dedup", 1, None);
+        optimizer.register("peephole", 1, None);
+        optimizer.register("inline", 2, None);
+        optimizer.register("inline-once", 3, None);
+        optimizer.register("outline", Self::SIZE, None);
+
+        optimizer
+    }
+
+    /// passes run in the order they are registered
+    pub fn register(&mut self, name: &str, level: usize, run: Option<PassFn>) {
+        self.passes.push(Pass {
+            name: name.into(),
+            level,
+            run
+        });
+    }
+
+    pub fn passes(&self) -> &[Pass] {
+        &self.passes
+    }
+
+    /// returns false if the pass does not exist
+    pub fn enable(&mut self, name: &str) -> bool {
+        self.disabled.remove(name);
+        self.enabled.insert(name.into());
+        self.has_pass(name)
+    }
+
+    /// returns false if the pass does not exist
+    pub fn disable(&mut self, name: &str) -> bool {
+        self.enabled.remove(name);
+        self.disabled.insert(name.into());
+        self.has_pass(name)
+    }
+
+    /// every pass that makes the output smaller
+    pub fn optimize_for_size(&mut self) {
+        self.level = 3;
+        for pass in &self.passes {
+            if pass.level == Self::SIZE && !self.disabled.contains(&pass.name) {
+                self.enabled.insert(pass.name.clone());
+            }
+        }
+    }
+
+    pub fn has_pass(&self, name: &str) -> bool {
+        self.passes.iter().any(|p| p.name == name)
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        if self.disabled.contains(name) {
+            return false;
+        }
+        if self.enabled.contains(name) {
+            return true;
+        }
+
+        self.passes.iter().any(|p| p.name == name && p.level <= self.level)
+    }
+
+    /// runs all enabled AST passes
+    pub fn optimize(&self, stmts: &mut Vec<Stmt>) {
+        for pass in &self.passes {
+            if let Some(run) = pass.run {
+                if self.is_enabled(&pass.name) {
+                    run(self, stmts);
+                    if self.dump {
+                        eprintln!("; AST after {}\n{:#?}", pass.name, stmts);
+                    }
+                }
+            }
+        }
+    }
+
+    fn constant_folding(&self, stmts: &mut Vec<Stmt>) {
+        let mut redefined = self.imported_words.clone();
+        Self::collect_definitions(stmts, &mut redefined);
+        Self::fold_constants(stmts, &redefined);
+    }
+
+    /// collects the names of all words defined or brought
+    /// into scope by the program. those words may not behave like the
+    /// builtins anymore and are therefore never folded
+    pub fn collect_definitions(stmts: &[Stmt], names: &mut HashSet<String>) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Define(define) => {
+                    names.insert(define.name.lexeme.clone());
+                    Self::collect_definitions(std::slice::from_ref(&define.body), names);
+                },
+                Stmt::Use(use_stmt) => {
+                    for word in &use_stmt.words {
+                        names.insert(word.lexeme.clone());
+                    }
+                },
+                Stmt::Block(block) => Self::collect_definitions(&block.body, names),
+                Stmt::Cond(cond) => {
+                    Self::collect_definitions(&cond.then_branch, names);
+                    Self::collect_definitions(&cond.else_branch, names);
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// replaces runs of number literals followed by pure arithmetic words
+    /// with a single literal.
+    /// folding never crosses a block boundary, blocks are folded on their own
+    fn fold_constants(stmts: &mut Vec<Stmt>, redefined: &HashSet<String>) {
+        let mut folded: Vec<Stmt> = vec![];
+        // amount of number literals at the end of folded
+        let mut literals = 0;
+
+        for mut stmt in stmts.drain(..) {
+            if Self::literal_number(&stmt).is_some() {
+                folded.push(stmt);
+                literals += 1;
+                continue;
+            }
+
+            if let Some((word, arity)) = Self::foldable(&stmt, redefined) {
+                if literals >= arity {
+                    let operands = folded.split_off(folded.len() - arity);
+                    match Self::fold(&word, &operands) {
+                        Some(result) => {
+                            folded.push(result);
+                            literals -= arity - 1;
+                        },
+                        None => {
+                            // e.g. division by 0. leave it to the runtime
+                            folded.extend(operands);
+                            folded.push(stmt);
+                            literals = 0;
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            Self::fold_children(&mut stmt, redefined);
+            folded.push(stmt);
+            literals = 0;
+        }
+
+        *stmts = folded;
+    }
+
+    fn fold_children(stmt: &mut Stmt, redefined: &HashSet<String>) {
+        match stmt {
+            Stmt::Block(block) => Self::fold_constants(&mut block.body, redefined),
+            Stmt::Define(define) => Self::fold_children(&mut define.body, redefined),
+            Stmt::If(if_stmt) => {
+                Self::fold_children(&mut if_stmt.then_block, redefined);
+                if let Some(else_block) = &mut if_stmt.else_block {
+                    Self::fold_children(else_block, redefined);
+                }
+            },
+            Stmt::Loop(loop_stmt) => Self::fold_children(&mut loop_stmt.block, redefined),
+            Stmt::While(while_stmt) => {
+                Self::fold_children(&mut while_stmt.condition, redefined);
+                Self::fold_children(&mut while_stmt.block, redefined);
+            },
+            Stmt::Case(case_stmt) => {
+                for branch in &mut case_stmt.branches {
+                    Self::fold_children(&mut branch.value, redefined);
+                    Self::fold_children(&mut branch.block, redefined);
+                }
+                Self::fold_children(&mut case_stmt.default, redefined);
+            },
+            Stmt::Do(do_stmt) => Self::fold_children(&mut do_stmt.block, redefined),
+            Stmt::Cond(cond) => {
+                Self::fold_constants(&mut cond.then_branch, redefined);
+                Self::fold_constants(&mut cond.else_branch, redefined);
+            },
+            Stmt::Expand(expand) => Self::fold_constants(&mut expand.body, redefined),
+            _ => {}
+        }
+    }
+
+    /// removes if statements whose condition is known at compile time.
+    /// only the taken branch is kept
+    fn dead_branches(&self, stmts: &mut Vec<Stmt>) {
+        let mut constants = Dictionary::new();
+        let mut mod_name = None;
+        Self::eliminate_branches(stmts, &mut constants, &mut mod_name);
+    }
+
+    fn eliminate_branches(stmts: &mut Vec<Stmt>, constants: &mut Dictionary, mod_name: &mut Option<String>) {
+        let mut result: Vec<Stmt> = vec![];
+
+        for mut stmt in stmts.drain(..) {
+            match &mut stmt {
+                Stmt::Define(define) => {
+                    // constants are evaluated just like the compiler does it.
+                    // every other definition shadows a constant of the same name
+                    let value = match define.mode {
+                        DefineMode::Constant => {
+                            let mut interpreter = Interpreter::with(vec![*define.body.clone()]);
+                            match interpreter.interprete() {
+                                Ok(_) => interpreter.pop(&define.name).unwrap_or(Object::Nil),
+                                _ => Object::Nil
+                            }
+                        },
+                        _ => Object::Nil
+                    };
+                    Self::eliminate_in(&mut define.body, constants, mod_name);
+                    constants.define(&define.name.lexeme, mod_name, &value);
+                },
+                Stmt::Mod(module) => *mod_name = Some(module.name.lexeme.clone()),
+                Stmt::Cond(cond) => {
+                    // either branch may be taken. words defined in
+                    // one of them are unknown afterwards
+                    let mut names = HashSet::new();
+                    for branch in [&mut cond.then_branch, &mut cond.else_branch] {
+                        let mut scope = constants.clone();
+                        Self::eliminate_branches(branch, &mut scope, &mut mod_name.clone());
+                        Self::collect_definitions(branch, &mut names);
+                    }
+                    for name in names {
+                        constants.define(&name, mod_name, &Object::Nil);
+                    }
+                },
+                Stmt::If(if_stmt) => {
+                    Self::eliminate_in(&mut if_stmt.then_block, constants, mod_name);
+                    if let Some(else_block) = &mut if_stmt.else_block {
+                        Self::eliminate_in(else_block, constants, mod_name);
+                    }
+
+                    let condition = result.last()
+                        .and_then(|prev| Self::constant_condition(prev, constants, mod_name));
+                    if let Some(condition) = condition {
+                        result.pop();
+                        if condition {
+                            result.push(*if_stmt.then_block.clone());
+                        } else if let Some(else_block) = &if_stmt.else_block {
+                            result.push(*else_block.clone());
+                        }
+                        continue;
+                    }
+                },
+                _ => Self::eliminate_in(&mut stmt, constants, mod_name)
+            }
+            result.push(stmt);
+        }
+
+        *stmts = result;
+    }
+
+    fn eliminate_in(stmt: &mut Stmt, constants: &mut Dictionary, mod_name: &mut Option<String>) {
+        match stmt {
+            Stmt::Block(block) => {
+                // blocks have their own scope
+                let mut scope = Dictionary::with(Some(Box::new(constants.clone())));
+                Self::eliminate_branches(&mut block.body, &mut scope, mod_name);
+            },
+            Stmt::Loop(loop_stmt) => Self::eliminate_in(&mut loop_stmt.block, constants, mod_name),
+            Stmt::While(while_stmt) => {
+                Self::eliminate_in(&mut while_stmt.condition, constants, mod_name);
+                Self::eliminate_in(&mut while_stmt.block, constants, mod_name);
+            },
+            Stmt::Case(case_stmt) => {
+                for branch in &mut case_stmt.branches {
+                    Self::eliminate_in(&mut branch.value, constants, mod_name);
+                    Self::eliminate_in(&mut branch.block, constants, mod_name);
+                }
+                Self::eliminate_in(&mut case_stmt.default, constants, mod_name);
+            },
+            Stmt::Do(do_stmt) => Self::eliminate_in(&mut do_stmt.block, constants, mod_name),
+            Stmt::Expand(expand) => Self::eliminate_branches(&mut expand.body, constants, mod_name),
+            _ => {}
+        }
+    }
+
+    /// the value of a literal or constant
+    fn constant_condition(stmt: &Stmt, constants: &Dictionary, mod_name: &Option<String>) -> Option<bool> {
+        let value = match stmt {
+            Stmt::Expr(ExprStmt { expr: Expr::Literal(literal) }) => literal.literal.literal.clone(),
+            Stmt::Expr(ExprStmt { expr: Expr::Word(word) }) => {
+                constants.get_any(&word.name, vec![&None, mod_name]).ok()?
+            },
+            _ => return None
+        };
+
+        match value {
+            Object::Number(_) | Object::Real(_) => Some(value.truthy()),
+            _ => None
+        }
+    }
+
+    fn literal_number(stmt: &Stmt) -> Option<ObjNumber> {
+        match stmt {
+            Stmt::Expr(ExprStmt { expr: Expr::Literal(literal) }) => {
+                match literal.literal.literal {
+                    Object::Number(n) => Some(n),
+                    _ => None
+                }
+            },
+            _ => None
+        }
+    }
+
+    fn foldable(stmt: &Stmt, redefined: &HashSet<String>) -> Option<(Token, usize)> {
+        match stmt {
+            Stmt::Expr(ExprStmt { expr: Expr::Word(word) }) => {
+                if redefined.contains(&word.name.lexeme) {
+                    return None;
+                }
+                FOLDABLE.iter()
+                    .find(|(name, _)| *name == word.name.lexeme)
+                    .map(|(_, arity)| (word.name.clone(), *arity))
+            },
+            _ => None
+        }
+    }
+
+    /// evaluates the word using the interpreter's builtins
+    /// so folding has the exact same semantics as constants
+    fn fold(word: &Token, operands: &[Stmt]) -> Option<Stmt> {
+        let mut interpreter = Interpreter::with(vec![]);
+        for operand in operands {
+            interpreter.push(Object::Number(Self::literal_number(operand)?));
+        }
+
+        let mut callable = match Interpreter::builtins().get(word, &None) {
+            Ok(Object::Callable(c)) => c,
+            _ => return None
+        };
+        callable.call(&mut interpreter, word).ok()?;
+
+        let value = interpreter.pop(word).ok()?;
+        let first = operands.first()?.token();
+        // the literal covers the operands and the word
+        Some(Stmt::Expr(ExprStmt::new(Expr::Literal(LiteralExpr::new(
+            Token::new(
+                TokenType::Number,
+                value.clone(),
+                &value.to_string(),
+                first.line,
+                first.start,
+                &first.path).with_span(first.span.merge(&word.span)))))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn optimize(source: &str) -> Vec<Stmt> {
+        optimize_with(source, Optimizer::new(1))
+    }
+
+    fn optimize_with(source: &str, optimizer: Optimizer) -> Vec<Stmt> {
+        let mut stmts = Parser::new(source, "").unwrap().parse().unwrap();
+        optimizer.optimize(&mut stmts);
+        stmts
+    }
+
+    fn literals(stmts: &[Stmt]) -> Vec<Option<ObjNumber>> {
+        stmts.iter().map(Optimizer::literal_number).collect()
+    }
+
+    #[test]
+    fn it_should_enable_passes_by_level() {
+        let optimizer = Optimizer::new(1);
+        assert!(optimizer.is_enabled("fold"));
+        assert!(!optimizer.is_enabled("inline"));
+
+        let optimizer = Optimizer::new(0);
+        assert!(!optimizer.is_enabled("fold"));
+
+        let optimizer = Optimizer::new(3);
+        assert!(optimizer.is_enabled("inline-once"));
+    }
+
+    #[test]
+    fn it_should_override_passes() {
+        let mut optimizer = Optimizer::new(1);
+        assert!(optimizer.enable("inline"));
+        assert!(optimizer.disable("fold"));
+        assert!(!optimizer.enable("unknown"));
+
+        assert!(optimizer.is_enabled("inline"));
+        assert!(!optimizer.is_enabled("fold"));
+
+        let mut stmts = Parser::new("1 2 +", "").unwrap().parse().unwrap();
+        optimizer.optimize(&mut stmts);
+        assert_eq!(literals(&stmts), vec![Some(1), Some(2), None]);
+    }
+
+    #[test]
+    fn it_should_fold_arithmetic() {
+        let stmts = optimize("1 2 + 4 *");
+        assert_eq!(literals(&stmts), vec![Some(12)]);
+    }
+
+    #[test]
+    fn it_should_fold_unary() {
+        let stmts = optimize("0 ~ 3 5 ^");
+        assert_eq!(literals(&stmts), vec![Some(-1), Some(6)]);
+    }
+
+    #[test]
+    fn it_should_only_fold_literal_operands() {
+        let stmts = optimize("a 1 + 2 3 -");
+        assert_eq!(literals(&stmts), vec![None, Some(1), None, Some(-1)]);
+    }
+
+    #[test]
+    fn it_should_not_fold_division_by_zero() {
+        let stmts = optimize("1 0 /");
+        assert_eq!(literals(&stmts), vec![Some(1), Some(0), None]);
+    }
+
+    #[test]
+    fn it_should_not_fold_redefined_words() {
+        let stmts = optimize(":i + :asm \"add\" ; 1 2 + 3 4 *");
+        assert_eq!(literals(&stmts[1..]), vec![Some(1), Some(2), None, Some(12)]);
+    }
+
+    #[test]
+    fn it_should_not_fold_imported_words() {
+        let mut optimizer = Optimizer::new(1);
+        optimizer.imported_words.insert("+".into());
+        let stmts = optimize_with("200 100 + 3 4 *", optimizer);
+        assert_eq!(literals(&stmts), vec![Some(200), Some(100), None, Some(12)]);
+    }
+
+    #[test]
+    fn it_should_not_fold_across_blocks() {
+        let mut optimizer = Optimizer::new(1);
+        optimizer.disable("dead-branch");
+        let stmts = optimize_with("1 if 2 + then 3 loop 4 * until", optimizer);
+        assert_eq!(literals(&stmts), vec![Some(1), None, Some(3), None]);
+
+        match &stmts[1] {
+            Stmt::If(if_stmt) => match &*if_stmt.then_block {
+                Stmt::Block(block) => assert_eq!(literals(&block.body), vec![Some(2), None]),
+                _ => panic!("Expected block")
+            },
+            _ => panic!("Expected if")
+        }
+    }
+
+    #[test]
+    fn it_should_keep_taken_branch() {
+        let stmts = optimize("1 if 2 else 3 then 0 if 4 else 5 then 0 if 6 then");
+        assert_eq!(stmts.len(), 2);
+
+        for (stmt, expected) in stmts.iter().zip(vec![2, 5]) {
+            match stmt {
+                Stmt::Block(block) => assert_eq!(literals(&block.body), vec![Some(expected)]),
+                _ => panic!("Expected block")
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_eliminate_constant_conditions() {
+        let stmts = optimize(":c DEBUG 0 ; : word DEBUG if log then 1 ; :c DEBUG 1 ; DEBUG if log then");
+        assert_eq!(stmts.len(), 4);
+
+        match &stmts[1] {
+            Stmt::Define(define) => match &*define.body {
+                Stmt::Block(block) => assert_eq!(literals(&block.body), vec![Some(1)]),
+                _ => panic!("Expected block")
+            },
+            _ => panic!("Expected define")
+        }
+        match &stmts[3] {
+            Stmt::Block(block) => assert_eq!(block.body.len(), 1),
+            _ => panic!("Expected block")
+        }
+    }
+
+    #[test]
+    fn it_should_not_eliminate_unknown_conditions() {
+        let stmts = optimize(":c DEBUG 1 ; : DEBUG 0 ; DEBUG if 1 then x if 1 then");
+        assert!(matches!(stmts[3], Stmt::If(_)));
+        assert!(matches!(stmts[5], Stmt::If(_)));
+    }
+
+    #[test]
+    fn it_should_use_constants_from_modules() {
+        let stmts = optimize(":mod m :c DEBUG 0 ; DEBUG if 1 then m::DEBUG if 1 then");
+        assert_eq!(stmts.len(), 2);
+    }
+
+    #[test]
+    fn it_should_fold_inside_definitions() {
+        let stmts = optimize(": word 2 3 * ;");
+        match &stmts[0] {
+            Stmt::Define(define) => match &*define.body {
+                Stmt::Block(block) => assert_eq!(literals(&block.body), vec![Some(6)]),
+                _ => panic!("Expected block")
+            },
+            _ => panic!("Expected define")
+        }
+    }
+
+    #[test]
+    fn it_should_not_use_constants_defined_in_conditional_compilation() {
+        let stmts = optimize(":c DEBUG 0 ; :ifdef NES :c DEBUG 1 ; :endif DEBUG if 1 2 + then");
+        assert!(matches!(stmts[3], Stmt::If(_)));
+
+        match &stmts[1] {
+            Stmt::Cond(cond) => assert_eq!(cond.then_branch.len(), 1),
+            _ => panic!("Expected conditional compilation")
+        }
     }
 }
@@ -0,0 +1,376 @@
+use super::stmt::*;
+use super::expr::*;
+use super::token::*;
+use super::object::*;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+/// the compiled size of a statement
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StmtSize {
+    Bytes(usize),
+    // compiled together with the statement before it.
+    // e.g. the @ of name @
+    Joined,
+    // more than one statement has the same position.
+    // e.g. statements generated by a parsing word or a macro
+    Unknown
+}
+
+/// the compiled size of statements by their position (line, start)
+pub type StmtSizes = HashMap<(usize, usize), StmtSize>;
+
+/// the positions (run, start) of sequences by (section, keys)
+type Positions = BTreeMap<(usize, Vec<String>), Vec<(usize, usize)>>;
+
+/// a sequence of statements that appears more than once
+/// in the bodies of regular words of the same module
+#[derive(Debug, Clone)]
+pub struct Sequence {
+    // index of the :mod section
+    pub section: usize,
+    pub keys: Vec<String>,
+    // the statements of the first occurrence
+    pub body: Vec<Stmt>,
+    pub occurrences: usize,
+    // compiled size of one occurrence
+    pub size: usize
+}
+
+impl Sequence {
+    /// bytes saved by replacing every occurrence with a call.
+    /// overhead is the size of compile and return
+    pub fn savings(&self, call: usize, overhead: usize) -> isize {
+        let occurrences = self.occurrences as isize;
+        let size = self.size as isize;
+        occurrences * size - (occurrences * call as isize + overhead as isize + size)
+    }
+}
+
+/// factors repeated sequences out into generated regular words
+pub struct Outliner;
+
+impl Outliner {
+    pub const PREFIX: &'static str = "__outline_";
+
+    /// statements that can be moved to another word.
+    /// the key is equal for equal statements
+    fn key(stmt: &Stmt) -> Option<String> {
+        match stmt {
+            Stmt::Expr(expr) => Self::expr_key(&expr.expr),
+            Stmt::Tick(tick) => Some(format!("' {}", tick.word.token().lexeme)),
+            _ => None
+        }
+    }
+
+    fn expr_key(expr: &Expr) -> Option<String> {
+        match expr {
+            Expr::Word(word) => Some(format!("w {}", word.name.lexeme)),
+            Expr::Literal(literal) => Some(format!("l {:?}", literal.literal.literal)),
+            Expr::Unary(unary) => Some(format!("{} {}", unary.op.lexeme, Self::expr_key(&unary.right)?))
+        }
+    }
+
+    fn outlinable(define: &DefineStmt) -> bool {
+        define.mode == DefineMode::Regular && !define.name.lexeme.starts_with(Self::PREFIX)
+    }
+
+    /// collects runs of outlinable statements
+    fn runs<'a>(stmt: &'a Stmt, runs: &mut Vec<Vec<&'a Stmt>>) {
+        match stmt {
+            Stmt::Block(block) => {
+                let mut run = vec![];
+                for stmt in &block.body {
+                    if Self::key(stmt).is_some() {
+                        run.push(stmt);
+                        continue;
+                    }
+
+                    if run.len() > 1 {
+                        runs.push(run);
+                    }
+                    run = vec![];
+                    Self::runs(stmt, runs);
+                }
+                if run.len() > 1 {
+                    runs.push(run);
+                }
+            },
+            Stmt::If(if_stmt) => {
+                Self::runs(&if_stmt.then_block, runs);
+                if let Some(else_block) = &if_stmt.else_block {
+                    Self::runs(else_block, runs);
+                }
+            },
+            Stmt::Loop(loop_stmt) => Self::runs(&loop_stmt.block, runs),
+            Stmt::While(while_stmt) => {
+                Self::runs(&while_stmt.condition, runs);
+                Self::runs(&while_stmt.block, runs);
+            },
+            Stmt::Case(case_stmt) => {
+                for branch in &case_stmt.branches {
+                    Self::runs(&branch.value, runs);
+                    Self::runs(&branch.block, runs);
+                }
+                Self::runs(&case_stmt.default, runs);
+            },
+            Stmt::Do(do_stmt) => Self::runs(&do_stmt.block, runs),
+            _ => {}
+        }
+    }
+
+    fn size(stmt: &Stmt, sizes: &StmtSizes) -> Option<StmtSize> {
+        let token = stmt.token();
+        sizes.get(&(token.line, token.start)).cloned()
+    }
+
+    /// true if the sequence at start separates a statement
+    /// from the one joined to it
+    fn splits(run: &[&Stmt], start: usize, end: usize, sizes: &StmtSizes) -> bool {
+        let joined = |i: usize| run.get(i).and_then(|s| Self::size(s, sizes)) == Some(StmtSize::Joined);
+        joined(start) || joined(end)
+    }
+
+    /// finds every sequence of at least two statements that
+    /// appears at least twice without overlapping.
+    /// sequences containing statements without a known size
+    /// or splitting joined statements are ignored
+    pub fn find(stmts: &[Stmt], sizes: &StmtSizes) -> Vec<Sequence> {
+        let mut runs = vec![];
+        let mut sections = vec![];
+        let mut section = 0;
+        for stmt in stmts {
+            match stmt {
+                Stmt::Mod(_) => section += 1,
+                Stmt::Define(define) if Self::outlinable(define) => {
+                    Self::runs(&define.body, &mut runs);
+                    sections.resize(runs.len(), section);
+                },
+                _ => {}
+            }
+        }
+
+        let mut found = Positions::new();
+        for (r, run) in runs.iter().enumerate() {
+            let keys: Vec<String> = run.iter().filter_map(|s| Self::key(s)).collect();
+            for start in 0..run.len() {
+                for end in start+2..=run.len() {
+                    found.entry((sections[r], keys[start..end].to_vec()))
+                        .or_default()
+                        .push((r, start));
+                }
+            }
+        }
+
+        let mut sequences = vec![];
+        for ((section, keys), positions) in found {
+            let mut occurrences = 0;
+            let mut last = None;
+            for (r, start) in &positions {
+                match last {
+                    Some((lr, end)) if lr == *r && *start < end => continue,
+                    _ => {}
+                }
+                occurrences += 1;
+                last = Some((*r, start + keys.len()));
+            }
+            // every occurrence is replaced
+            if occurrences < 2 || positions.iter().any(|(r, start)| Self::splits(&runs[*r], *start, start + keys.len(), sizes)) {
+                continue;
+            }
+
+            let (r, start) = positions[0];
+            let body = &runs[r][start..start+keys.len()];
+            let size = body.iter()
+                .map(|s| match Self::size(s, sizes)? {
+                    StmtSize::Bytes(size) => Some(size),
+                    StmtSize::Joined => Some(0),
+                    StmtSize::Unknown => None
+                })
+                .sum::<Option<usize>>();
+            if let Some(size) = size {
+                sequences.push(Sequence {
+                    section,
+                    keys,
+                    body: body.iter().map(|s| (*s).clone()).collect(),
+                    occurrences,
+                    size
+                });
+            }
+        }
+
+        sequences
+    }
+
+    /// replaces every occurrence with a call to a new word.
+    /// the word is defined right before the first word using it
+    pub fn replace(stmts: &mut Vec<Stmt>, sequence: &Sequence, name: &str) {
+        let first_token = sequence.body[0].token();
+        // generated tokens have no position in the source
+        let token = Token::new(TokenType::Word, Object::Word(name.into()), name,
+            first_token.line, usize::MAX, &first_token.path);
+        let call = Stmt::Expr(ExprStmt::new(Expr::Word(WordExpr::new(token.clone()))));
+
+        let mut first = None;
+        let mut section = 0;
+        for (i, stmt) in stmts.iter_mut().enumerate() {
+            match stmt {
+                Stmt::Mod(_) => section += 1,
+                Stmt::Define(define) if section == sequence.section && Self::outlinable(define) => {
+                    let found = Self::replace_in(&mut define.body, &sequence.keys, &call);
+                    if found && first.is_none() {
+                        first = Some(i);
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        if let Some(i) = first {
+            let mut noinline = token.clone();
+            noinline.token_type = TokenType::NoInline;
+            let body = Stmt::Block(BlockStmt::new(sequence.body.clone(), token.clone()));
+            stmts.insert(i, Stmt::Define(DefineStmt::with(token, Box::new(body),
+                DefineMode::Regular, vec![noinline])));
+        }
+    }
+
+    fn matches(stmts: &[Stmt], keys: &[String]) -> bool {
+        stmts.len() >= keys.len()
+            && stmts.iter().zip(keys).all(|(stmt, key)| Self::key(stmt).as_ref() == Some(key))
+    }
+
+    fn replace_in(stmt: &mut Stmt, keys: &[String], call: &Stmt) -> bool {
+        match stmt {
+            Stmt::Block(block) => {
+                let mut found = false;
+                let mut i = 0;
+                while i < block.body.len() {
+                    if Self::matches(&block.body[i..], keys) {
+                        block.body.splice(i..i+keys.len(), vec![call.clone()]);
+                        found = true;
+                    } else {
+                        found |= Self::replace_in(&mut block.body[i], keys, call);
+                    }
+                    i += 1;
+                }
+                found
+            },
+            Stmt::If(if_stmt) => {
+                let mut found = Self::replace_in(&mut if_stmt.then_block, keys, call);
+                if let Some(else_block) = &mut if_stmt.else_block {
+                    found |= Self::replace_in(else_block, keys, call);
+                }
+                found
+            },
+            Stmt::Loop(loop_stmt) => Self::replace_in(&mut loop_stmt.block, keys, call),
+            Stmt::While(while_stmt) => {
+                let found = Self::replace_in(&mut while_stmt.condition, keys, call);
+                Self::replace_in(&mut while_stmt.block, keys, call) || found
+            },
+            Stmt::Case(case_stmt) => {
+                let mut found = false;
+                for branch in &mut case_stmt.branches {
+                    found |= Self::replace_in(&mut branch.value, keys, call);
+                    found |= Self::replace_in(&mut branch.block, keys, call);
+                }
+                Self::replace_in(&mut case_stmt.default, keys, call) || found
+            },
+            Stmt::Do(do_stmt) => Self::replace_in(&mut do_stmt.block, keys, call),
+            _ => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::parser::Parser;
+
+    fn parse(source: &str) -> (Vec<Stmt>, StmtSizes) {
+        let stmts = Parser::new(source, "").unwrap().parse().unwrap();
+
+        // every statement is one byte
+        let mut sizes = HashMap::new();
+        for start in 0..source.len() {
+            sizes.insert((1, start), StmtSize::Bytes(1));
+        }
+        (stmts, sizes)
+    }
+
+    fn find(stmts: &[Stmt], sizes: &StmtSizes, keys: &[&str]) -> Option<Sequence> {
+        Outliner::find(stmts, sizes).into_iter()
+            .find(|s| s.keys.iter().map(|k| k.as_ref()).collect::<Vec<&str>>() == keys)
+    }
+
+    #[test]
+    fn it_should_find_repeated_sequences() {
+        let (stmts, sizes) = parse(": a 1 b c ; : d b c if b c then ; : e b 1 ;");
+
+        let sequence = find(&stmts, &sizes, &["w b", "w c"]).unwrap();
+        assert_eq!(sequence.occurrences, 3);
+        assert_eq!(sequence.size, 2);
+        assert_eq!(sequence.savings(1, 1), 6 - (3 + 1 + 2));
+
+        assert!(find(&stmts, &sizes, &["l Number(1)", "w b"]).is_none());
+    }
+
+    #[test]
+    fn it_should_not_count_overlapping_sequences() {
+        // sequences in different modules are not the same
+        let (stmts, sizes) = parse(": a b b b ; :mod m : c b b ;");
+        assert!(find(&stmts, &sizes, &["w b", "w b"]).is_none());
+
+        let (stmts, sizes) = parse(": a b b b b ;");
+        assert_eq!(find(&stmts, &sizes, &["w b", "w b"]).unwrap().occurrences, 2);
+    }
+
+    #[test]
+    fn it_should_not_split_joined_statements() {
+        // v @ is compiled as a single fetch
+        let (stmts, mut sizes) = parse(": a x v @ ; : b x v @ ;");
+        for start in [8, 20] {
+            sizes.insert((1, start), StmtSize::Joined);
+        }
+
+        assert!(find(&stmts, &sizes, &["w x", "w v"]).is_none());
+        assert!(find(&stmts, &sizes, &["w v", "w @"]).is_some());
+        assert_eq!(find(&stmts, &sizes, &["w x", "w v", "w @"]).unwrap().size, 2);
+    }
+
+    #[test]
+    fn it_should_ignore_statements_of_unknown_size() {
+        let (stmts, mut sizes) = parse(": a x y ; : b x y ;");
+        sizes.insert((1, 6), StmtSize::Unknown);
+
+        assert!(find(&stmts, &sizes, &["w x", "w y"]).is_none());
+    }
+
+    #[test]
+    fn it_should_replace_sequences() {
+        let (mut stmts, sizes) = parse(": a x y z ; : b y z x ; : c ; y z");
+        let sequence = find(&stmts, &sizes, &["w y", "w z"]).unwrap();
+        Outliner::replace(&mut stmts, &sequence, "__outline_0");
+
+        assert_eq!(stmts.len(), 6);
+        match &stmts[0] {
+            Stmt::Define(define) => {
+                assert_eq!(define.name.lexeme, "__outline_0");
+                assert!(define.has_attribute(TokenType::NoInline));
+            },
+            _ => panic!("Expected define")
+        }
+        match &stmts[2] {
+            Stmt::Define(define) => match &*define.body {
+                Stmt::Block(block) => {
+                    assert_eq!(block.body.len(), 2);
+                    assert_eq!(block.body[0].token().lexeme, "__outline_0");
+                },
+                _ => panic!("Expected block")
+            },
+            _ => panic!("Expected define")
+        }
+        // top-level code is not changed
+        assert_eq!(stmts[4].token().lexeme, "y");
+    }
+}
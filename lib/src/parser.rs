@@ -5,25 +5,81 @@
  */
 
 use super::scanner::Scanner;
-use super::token::{Token, TokenType};
+use super::token::{Token, TokenType, Span};
 use super::error::{ExecError, ErrorType, ErrorList, BoxResult};
 use super::expr::*;
 use super::stmt::*;
+use super::object::Object;
+use super::effect::StackEffect;
+use super::interpreter::Interpreter;
+use super::macros::Macro;
+use super::filesystem::{FileSystemManager, LocalFileSystem};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::fmt;
+use std::path::Path;
+
+/// a word declared with :parsing.
+/// it consumes the next tokens and its body is interpreted
+/// to build the statements that replace it
+#[derive(Debug, Clone)]
+pub struct ParsingWord {
+    pub tokens: usize,
+    pub body: Stmt
+}
 
-#[derive(Debug)]
 pub struct Parser {
     current: usize,
     tokens: Vec<Token>,
+    path: String,
+    // definitions and blocks that are not closed yet
+    open: Vec<Token>,
+    // the word that is being defined. recurse calls it
+    defining: Option<(Token, DefineMode)>,
+    // comments by the index of the token that follows them
+    comments: HashMap<usize, Vec<Token>>,
+    pub parsing: HashMap<String, ParsingWord>,
+    pub macros: HashMap<String, Macro>,
+    // imported files are read to find their parsing words
+    filesystem: Rc<dyn FileSystemManager>
+}
+
+impl fmt::Debug for Parser {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Parser {} {:?}", self.path, self.tokens)
+    }
 }
 
 impl Parser {
     pub fn new(source: &str, path: &str) -> Result<Parser, ErrorList> {
+        Self::with_filesystem(source, path, Rc::new(LocalFileSystem))
+    }
+
+    pub fn with_filesystem(source: &str, path: &str, filesystem: Rc<dyn FileSystemManager>) -> Result<Parser, ErrorList> {
         let mut scanner = Scanner::new(source, path);
-        let tokens = scanner.scan()?;
+        let mut tokens = vec![];
+        let mut comments: HashMap<usize, Vec<Token>> = HashMap::new();
+
+        // comments are not part of the token stream.
+        // definitions look them up to find their stack effect
+        for token in scanner.scan()? {
+            if token.token_type == TokenType::Comment {
+                comments.entry(tokens.len()).or_default().push(token);
+            } else {
+                tokens.push(token);
+            }
+        }
 
         Ok(Self {
             current: 0,
-            tokens
+            tokens,
+            path: path.into(),
+            open: vec![],
+            defining: None,
+            comments,
+            parsing: HashMap::new(),
+            macros: HashMap::new(),
+            filesystem
         })
     }
 
@@ -32,10 +88,19 @@ impl Parser {
         let mut errors = vec![];
 
         while !self.is_at_end() {
-            match self.stmt() {
-                Ok(expr) => exprs.push(expr),
+            // the rest of a conditional compilation that had an error
+            if !errors.is_empty() && self.is_match(vec![TokenType::CondElse, TokenType::CondEndIf]) {
+                continue;
+            }
+
+            let start = self.current;
+            match self.next_stmts() {
+                Ok(stmts) => exprs.extend(stmts),
                 Err(err) => {
                     errors.push(err);
+                    if self.current == start {
+                        self.advance();
+                    }
                     self.sync();
                 }
             }
@@ -48,6 +113,40 @@ impl Parser {
         return Ok(exprs);
     }
 
+    /// parsing words and their declarations
+    /// may result in any number of statements
+    fn next_stmts(&mut self) -> BoxResult<Vec<Stmt>> {
+        // a ; or a new definition inside of a block means
+        // the innermost block was never closed
+        if let Some(open) = self.open.last() {
+            let next = self.peek().token_type;
+            if next == TokenType::EndDefine
+                || next == TokenType::CondElse
+                || next == TokenType::CondEndIf
+                || Self::is_boundary(next) {
+                return Err(Box::new(ExecError::new(ErrorType::UnterminatedBlock, open.clone())));
+            }
+        }
+
+        if self.is_match(vec![TokenType::Parsing]) {
+            self.parsing_stmt()?;
+            return Ok(vec![]);
+        } else if self.is_match(vec![TokenType::Macro]) {
+            self.macro_stmt()?;
+            return Ok(vec![]);
+        } else if self.check(TokenType::Word)
+            && self.parsing.contains_key(&self.peek().lexeme) {
+            let token = self.advance().clone();
+            return self.expand(&token);
+        } else if self.check(TokenType::Word)
+            && self.macros.contains_key(&self.peek().lexeme) {
+            let token = self.advance().clone();
+            return Ok(vec![self.expand_macro(token)?]);
+        }
+
+        return Ok(vec![self.stmt()?]);
+    }
+
     fn stmt(&mut self) -> BoxResult<Stmt> {
         if self.is_match(vec![TokenType::StartDefine]) {
             return self.define_stmt();
@@ -55,10 +154,28 @@ impl Parser {
             return self.define_inline_stmt();
         } else if self.is_match(vec![TokenType::StartConstDefine]) {
             return self.define_const_stmt();
+        } else if self.is_match(vec![TokenType::Let]) {
+            return self.let_stmt();
+        } else if self.is_match(vec![TokenType::Forward]) {
+            return self.forward_stmt();
+        } else if self.is_match(vec![TokenType::CondIf, TokenType::CondIfDef]) {
+            return self.cond_stmt();
         } else if self.is_match(vec![TokenType::If]) {
             return self.if_stmt();
         } else if self.is_match(vec![TokenType::Loop]) {
             return self.loop_stmt();
+        } else if self.is_match(vec![TokenType::Begin]) {
+            return self.begin_stmt();
+        } else if self.is_match(vec![TokenType::Case]) {
+            return self.case_stmt();
+        } else if self.is_match(vec![TokenType::Do]) {
+            return self.do_stmt();
+        } else if self.is_match(vec![TokenType::Index]) {
+            return self.index_stmt();
+        } else if self.is_match(vec![TokenType::Recurse]) {
+            return self.recurse_stmt();
+        } else if self.is_match(vec![TokenType::Exit]) {
+            return self.exit_stmt();
         } else if self.is_match(vec![TokenType::Asm]) {
             return self.asm_stmt();
         } else if self.is_match(vec![TokenType::Mod]) {
@@ -69,6 +186,8 @@ impl Parser {
             return self.use_stmt();
         } else if self.is_match(vec![TokenType::Tick]) {
             return self.tick_stmt();
+        } else if self.is_match(vec![TokenType::Peephole]) {
+            return self.peephole_stmt();
         } else {
             // default case
             let expr = match self.expr() {
@@ -81,59 +200,293 @@ impl Parser {
     }
 
     fn block_stmt(&mut self, delim: TokenType) -> BoxResult<Stmt> {
+        let start = self.peek().clone();
         let mut block = vec![];
         while !self.check(delim)
             && !self.is_at_end() {
-            block.push(self.stmt()?);
+            block.extend(self.next_stmts()?);
         }
         self.consume(delim, ErrorType::UnterminatedBlock)?;
-        return Ok(Stmt::Block(BlockStmt::new(block, self.previous().clone())));
+        let mut block = BlockStmt::new(block, self.previous().clone());
+        block.span = self.span_from(&start);
+        return Ok(Stmt::Block(block));
     }
 
     fn define_stmt(&mut self) -> BoxResult<Stmt> {
+        let start = self.previous().clone();
         // eat the first expr which should be a word!
         let name = self.advance().clone();
         if name.token_type != TokenType::Word {
             return Err(Box::new(ExecError::new(ErrorType::ExpectedName, name)));
         }
 
+        let (attributes, effect) = self.attributes(self.current - 2)?;
+        self.open.push(name.clone());
+        self.defining = Some((name.clone(), DefineMode::Regular));
         let block = Box::new(self.block_stmt(TokenType::EndDefine)?);
-        return Ok(Stmt::Define(DefineStmt::new(name, block, DefineMode::Regular)));
+        self.defining = None;
+        self.open.pop();
+        let mut define = DefineStmt::with(name, block, DefineMode::Regular, attributes);
+        define.effect = effect;
+        define.span = self.span_from(&start);
+        return Ok(Stmt::Define(define));
     }
 
     fn define_inline_stmt(&mut self) -> BoxResult<Stmt> {
+        let start = self.previous().clone();
         // eat the first expr which should be a word!
         let name = self.advance().clone();
         if name.token_type != TokenType::Word {
             return Err(Box::new(ExecError::new(ErrorType::ExpectedName, name)));
         }
 
+        let (attributes, effect) = self.attributes(self.current - 2)?;
+        self.open.push(name.clone());
+        self.defining = Some((name.clone(), DefineMode::Inline));
         let block = Box::new(self.block_stmt(TokenType::EndDefine)?);
-        return Ok(Stmt::Define(DefineStmt::new(name, block, DefineMode::Inline)));
+        self.defining = None;
+        self.open.pop();
+        let mut define = DefineStmt::with(name, block, DefineMode::Inline, attributes);
+        define.effect = effect;
+        define.span = self.span_from(&start);
+        return Ok(Stmt::Define(define));
     }
 
     fn define_const_stmt(&mut self) -> BoxResult<Stmt> {
+        let start = self.previous().clone();
         // eat the first expr which should be a word!
         let name = self.advance().clone();
         if name.token_type != TokenType::Word {
             return Err(Box::new(ExecError::new(ErrorType::ExpectedName, name)));
         }
 
+        let (attributes, effect) = self.attributes(self.current - 2)?;
+        self.open.push(name.clone());
+        self.defining = Some((name.clone(), DefineMode::Constant));
         let block = Box::new(self.block_stmt(TokenType::EndDefine)?);
-        return Ok(Stmt::Define(DefineStmt::new(name, block, DefineMode::Constant)));
+        self.defining = None;
+        self.open.pop();
+        let mut define = DefineStmt::with(name, block, DefineMode::Constant, attributes);
+        define.effect = effect;
+        define.span = self.span_from(&start);
+        return Ok(Stmt::Define(define));
+    }
+
+    fn let_stmt(&mut self) -> BoxResult<Stmt> {
+        let token = self.previous().clone();
+        let name = self.consume(TokenType::Word, ErrorType::ExpectedName)?;
+        if !self.is_match(vec![TokenType::I8, TokenType::I16, TokenType::I32, TokenType::I64]) {
+            return Err(Box::new(ExecError::new(ErrorType::UnexpectedToken, self.peek().clone())));
+        }
+        let width = self.previous().clone();
+        return Ok(Stmt::Let(LetStmt::new(name, width, token)));
+    }
+
+    fn forward_stmt(&mut self) -> BoxResult<Stmt> {
+        let token = self.previous().clone();
+        let name = self.consume(TokenType::Word, ErrorType::ExpectedName)?;
+        let (_, effect) = self.attributes(self.current - 2)?;
+        self.consume(TokenType::EndDefine, ErrorType::UnterminatedBlock)?;
+        let mut stmt = ForwardStmt::new(name, token);
+        stmt.effect = effect;
+        stmt.span = self.span_from(&stmt.token);
+        return Ok(Stmt::Forward(stmt));
+    }
+
+    /// the condition is evaluated by the compiler.
+    /// both branches may contain definitions if the :if is at the top-level
+    fn cond_stmt(&mut self) -> BoxResult<Stmt> {
+        let token = self.previous().clone();
+        let condition = if token.token_type == TokenType::CondIfDef {
+            Condition::Defined(self.consume(TokenType::Word, ErrorType::ExpectedName)?)
+        } else {
+            self.open.push(token.clone());
+            let condition = self.block_stmt(TokenType::EndDefine)?;
+            self.open.pop();
+            Condition::Expr(Box::new(condition))
+        };
+
+        let then_branch = self.cond_branch()?;
+        let else_branch = if self.is_match(vec![TokenType::CondElse]) {
+            self.cond_branch()?
+        } else {
+            vec![]
+        };
+        self.consume(TokenType::CondEndIf, ErrorType::UnterminatedBlock)?;
+
+        let mut stmt = CondStmt::new(condition, then_branch, else_branch, token);
+        stmt.span = self.span_from(&stmt.token);
+        return Ok(Stmt::Cond(stmt));
+    }
+
+    fn cond_branch(&mut self) -> BoxResult<Vec<Stmt>> {
+        let mut stmts = vec![];
+        while !self.check(TokenType::CondElse)
+            && !self.check(TokenType::CondEndIf)
+            && !self.is_at_end() {
+            stmts.extend(self.next_stmts()?);
+        }
+        Ok(stmts)
+    }
+
+    /// attributes directly follow the name of a definition.
+    /// a stack comment between the name and the body or right
+    /// before the definition declares the effect unless :effect is used.
+    /// start is the index of the token that started the definition
+    fn attributes(&mut self, start: usize) -> BoxResult<(Vec<Token>, Option<StackEffect>)> {
+        let mut attributes = vec![];
+        let mut effect = None;
+        while self.is_match(vec![TokenType::Entry, TokenType::NoInline, TokenType::Effect]) {
+            attributes.push(self.previous().clone());
+            if self.previous().token_type == TokenType::Effect {
+                let inputs = self.effect_count()?;
+                let outputs = self.effect_count()?;
+                effect = Some(StackEffect::new(inputs, outputs));
+            }
+        }
+
+        if effect.is_none() {
+            effect = (start + 1..=self.current)
+                .chain(std::iter::once(start))
+                .filter_map(|i| self.comments.get(&i))
+                .flatten()
+                .find_map(|comment| StackEffect::from_comment(&comment.literal.to_string()));
+        }
+        Ok((attributes, effect))
+    }
+
+    fn effect_count(&mut self) -> BoxResult<usize> {
+        let token = self.advance().clone();
+        match token.literal {
+            Object::Number(n) if token.token_type == TokenType::Number && n >= 0 => Ok(n as usize),
+            _ => Err(Box::new(ExecError::new(ErrorType::BadNumber, token)))
+        }
     }
 
     fn mod_stmt(&mut self) -> BoxResult<Stmt> {
+        let start = self.previous().clone();
         let mod_name = self.consume(TokenType::Word, ErrorType::UnexpectedToken)?;
-        return Ok(Stmt::Mod(ModStmt::new(mod_name)));
+        let mut stmt = ModStmt::new(mod_name);
+        stmt.span = self.span_from(&start);
+        return Ok(Stmt::Mod(stmt));
     }
 
     fn import_stmt(&mut self) -> BoxResult<Stmt> {
+        let start = self.previous().clone();
         let mod_name = self.consume(TokenType::Str, ErrorType::UnexpectedToken)?;
-        return Ok(Stmt::Import(ImportStmt::new(mod_name.literal.clone(), mod_name)));
+        let module = self.import_parsing_words(&mod_name);
+        let mut stmt = ImportStmt::new(mod_name.literal.clone(), mod_name);
+        stmt.module = module;
+        stmt.span = self.span_from(&start);
+        return Ok(Stmt::Import(stmt));
+    }
+
+    /// parsing words have to be known before the compiler imports the file.
+    /// the parsed file is kept so the compiler does not parse it again.
+    /// errors are reported once the compiler imports it, an import
+    /// in a skipped conditional compilation is never compiled
+    fn import_parsing_words(&mut self, token: &Token) -> Option<Rc<ParsedModule>> {
+        let path = Path::new(&self.path).parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(token.literal.to_string());
+        let path = path.to_string_lossy();
+
+        let source = self.filesystem.read_file(&path).ok()?;
+        let mut parser = Parser::with_filesystem(&source, &path, self.filesystem.clone()).ok()?;
+        let stmts = parser.parse();
+        self.parsing.extend(parser.parsing);
+        self.macros.extend(parser.macros);
+
+        Some(Rc::new(ParsedModule {
+            source,
+            stmts: stmts.ok()?
+        }))
+    }
+
+    fn parsing_stmt(&mut self) -> BoxResult<()> {
+        let name = self.consume(TokenType::Word, ErrorType::ExpectedName)?;
+        let tokens = self.effect_count()?;
+        self.open.push(name.clone());
+        let body = self.block_stmt(TokenType::EndDefine)?;
+        self.open.pop();
+        self.parsing.insert(name.lexeme, ParsingWord { tokens, body });
+        Ok(())
+    }
+
+    fn macro_stmt(&mut self) -> BoxResult<()> {
+        let name = self.consume(TokenType::Word, ErrorType::ExpectedName)?;
+        let mut params = vec![];
+        while self.check(TokenType::Word) {
+            params.push(self.advance().clone());
+        }
+        self.consume(TokenType::EndDefine, ErrorType::UnterminatedBlock)?;
+
+        self.open.push(name.clone());
+        let body = match self.block_stmt(TokenType::EndDefine)? {
+            Stmt::Block(block) => block.body,
+            body => vec![body]
+        };
+        self.open.pop();
+        self.macros.insert(name.lexeme, Macro { params, body });
+        Ok(())
+    }
+
+    /// every argument is either a single statement
+    /// or any number of statements in [ ]
+    fn expand_macro(&mut self, token: Token) -> BoxResult<Stmt> {
+        let word = self.macros[&token.lexeme].clone();
+        let mut args = vec![];
+
+        for _ in 0..word.params.len() {
+            if self.is_at_end() || self.check(TokenType::EndDefine) {
+                return Err(Box::new(ExecError::new(ErrorType::UnexpectedToken, self.peek().clone())));
+            }
+
+            if self.is_match(vec![TokenType::StartQuote]) {
+                self.open.push(self.previous().clone());
+                let mut arg = vec![];
+                while !self.check(TokenType::EndQuote) && !self.is_at_end() {
+                    arg.extend(self.next_stmts()?);
+                }
+                self.consume(TokenType::EndQuote, ErrorType::UnterminatedBlock)?;
+                self.open.pop();
+                args.push(arg);
+            } else {
+                args.push(self.next_stmts()?);
+            }
+        }
+
+        let mut stmt = word.expand(token, args);
+        stmt.span = self.span_from(&stmt.token);
+        return Ok(Stmt::Expand(stmt));
+    }
+
+    /// interpretes the body of a parsing word with the tokens
+    /// following it on the stack
+    fn expand(&mut self, token: &Token) -> BoxResult<Vec<Stmt>> {
+        let word = self.parsing[&token.lexeme].clone();
+        let mut interpreter = Interpreter::with(vec![word.body]);
+
+        for _ in 0..word.tokens {
+            if self.is_at_end() {
+                return Err(Box::new(ExecError::new(ErrorType::UnexpectedToken, self.peek().clone())));
+            }
+
+            let arg = self.advance().clone();
+            interpreter.push(match arg.token_type {
+                TokenType::Number | TokenType::Real | TokenType::Str => arg.literal,
+                _ => Object::Word(arg.lexeme)
+            });
+        }
+
+        if let Err(mut err) = interpreter.interprete() {
+            return Err(err.errors.remove(0));
+        }
+        return Ok(interpreter.emitted);
     }
 
     fn use_stmt(&mut self) -> BoxResult<Stmt> {
+        let start = self.previous().clone();
         let mod_name = self.consume(TokenType::Word, ErrorType::UnexpectedToken)?;
 
         // scan all words after mod_name
@@ -146,23 +499,50 @@ impl Parser {
         // use needs  to be terminated
         self.consume(TokenType::EndDefine, ErrorType::UnterminatedBlock)?;
 
-        return Ok(Stmt::Use(UseStmt::new(mod_name, words)));
+        let mut stmt = UseStmt::new(mod_name, words);
+        stmt.span = self.span_from(&start);
+        return Ok(Stmt::Use(stmt));
+    }
+
+    fn peephole_stmt(&mut self) -> BoxResult<Stmt> {
+        let token = self.previous().clone();
+        let first = self.consume(TokenType::Word, ErrorType::ExpectedName)?;
+        let second = self.consume(TokenType::Word, ErrorType::ExpectedName)?;
+
+        // everything up to ; is the replacement
+        let mut replacement = vec![];
+        while self.check(TokenType::Word)
+            && !self.is_at_end() {
+            replacement.push(self.advance().clone());
+        }
+        self.consume(TokenType::EndDefine, ErrorType::UnterminatedBlock)?;
+
+        let mut stmt = PeepholeStmt::new(first, second, replacement, token);
+        stmt.span = self.span_from(&stmt.token);
+        return Ok(Stmt::Peephole(stmt));
     }
 
     fn asm_stmt(&mut self) -> BoxResult<Stmt> {
+        let start = self.previous().clone();
         let code = self.consume(TokenType::Str, ErrorType::UnexpectedToken)?;
-        return Ok(Stmt::Asm(AsmStmt::new(code.literal.clone(), code)));
+        let mut stmt = AsmStmt::new(code.literal.clone(), code);
+        stmt.span = self.span_from(&start);
+        return Ok(Stmt::Asm(stmt));
     }
 
     fn if_stmt(&mut self) -> BoxResult<Stmt> {
+        let token = self.previous().clone();
+        self.open.push(token.clone());
+        let start = self.peek().clone();
         // if body ends at then or else
         let mut block = vec![];
         while !self.check(TokenType::Then)
             && !self.check(TokenType::Else)
             && !self.is_at_end() {
-            block.push(self.stmt()?);
+            block.extend(self.next_stmts()?);
         }
-        let if_block = Box::new(Stmt::Block(BlockStmt::new(block, self.previous().clone())));
+        let mut if_block = BlockStmt::new(block, self.previous().clone());
+        if_block.span = self.span_from(&start);
         let mut else_block = None;
         if self.check(TokenType::Else) {
             self.advance();
@@ -170,13 +550,146 @@ impl Parser {
         } else {
             self.consume(TokenType::Then, ErrorType::UnterminatedBlock)?;
         }
+        self.open.pop();
 
-        Ok(Stmt::If(IfStmt::new(if_block, else_block, self.previous().clone())))
+        let mut stmt = IfStmt::new(Box::new(Stmt::Block(if_block)), else_block, token);
+        stmt.span = self.span_from(&stmt.token);
+        Ok(Stmt::If(stmt))
     }
 
     fn loop_stmt(&mut self) -> BoxResult<Stmt> {
+        let token = self.previous().clone();
+        self.open.push(token.clone());
         let loop_body = Box::new(self.block_stmt(TokenType::Until)?);
-        return Ok(Stmt::Loop(LoopStmt::new(loop_body, self.previous().clone())));
+        self.open.pop();
+        let mut stmt = LoopStmt::new(loop_body, token);
+        stmt.span = self.span_from(&stmt.token);
+        return Ok(Stmt::Loop(stmt));
+    }
+
+    /// begin ... until works like loop ... until.
+    /// begin <condition> while <block> repeat checks the condition first
+    fn begin_stmt(&mut self) -> BoxResult<Stmt> {
+        let token = self.previous().clone();
+        self.open.push(token.clone());
+        let start = self.peek().clone();
+        let mut block = vec![];
+        while !self.check(TokenType::Until)
+            && !self.check(TokenType::While)
+            && !self.is_at_end() {
+            block.extend(self.next_stmts()?);
+        }
+        let span = self.span_from(&start);
+
+        if self.is_match(vec![TokenType::Until]) {
+            self.open.pop();
+            let mut block = BlockStmt::new(block, self.previous().clone());
+            block.span = span;
+            let mut stmt = LoopStmt::new(Box::new(Stmt::Block(block)), token);
+            stmt.span = self.span_from(&stmt.token);
+            return Ok(Stmt::Loop(stmt));
+        }
+
+        let delim = self.consume(TokenType::While, ErrorType::UnterminatedBlock)?;
+        let mut condition = BlockStmt::new(block, delim);
+        condition.span = span;
+        let body = self.block_stmt(TokenType::Repeat)?;
+        self.open.pop();
+        let mut stmt = WhileStmt::new(Box::new(Stmt::Block(condition)), Box::new(body), token);
+        stmt.span = self.span_from(&stmt.token);
+        return Ok(Stmt::While(stmt));
+    }
+
+    /// the statements before of are the value of a branch,
+    /// the statements before endcase are the default
+    fn case_stmt(&mut self) -> BoxResult<Stmt> {
+        let token = self.previous().clone();
+        self.open.push(token.clone());
+        let mut branches = vec![];
+        loop {
+            let start = self.peek().clone();
+            let mut block = vec![];
+            while !self.check(TokenType::Of)
+                && !self.check(TokenType::EndCase)
+                && !self.is_at_end() {
+                block.extend(self.next_stmts()?);
+            }
+            let span = self.span_from(&start);
+
+            if self.is_match(vec![TokenType::EndCase]) {
+                self.open.pop();
+                let mut default = BlockStmt::new(block, self.previous().clone());
+                default.span = span;
+                let mut stmt = CaseStmt::new(branches, Box::new(Stmt::Block(default)), token);
+                stmt.span = self.span_from(&stmt.token);
+                return Ok(Stmt::Case(stmt));
+            }
+
+            let of = self.consume(TokenType::Of, ErrorType::UnterminatedBlock)?;
+            let mut value = BlockStmt::new(block, of.clone());
+            value.span = span;
+            let body = self.block_stmt(TokenType::EndOf)?;
+            branches.push(OfBranch::new(Box::new(Stmt::Block(value)), Box::new(body), of));
+        }
+    }
+
+    /// the body of a do loop ends at loop or +loop.
+    /// begin ... until has to be used for loops inside of it
+    fn do_stmt(&mut self) -> BoxResult<Stmt> {
+        let token = self.previous().clone();
+        let start = self.peek().clone();
+
+        self.open.push(token.clone());
+        let mut block = vec![];
+        while !self.check(TokenType::Loop)
+            && !self.check(TokenType::PlusLoop)
+            && !self.is_at_end() {
+            block.extend(self.next_stmts()?);
+        }
+
+        let mut block = BlockStmt::new(block, self.previous().clone());
+        block.span = self.span_from(&start);
+        let plus = self.check(TokenType::PlusLoop);
+        if !self.is_match(vec![TokenType::Loop, TokenType::PlusLoop]) {
+            return Err(Box::new(ExecError::new(ErrorType::UnterminatedBlock, self.previous().clone())));
+        }
+        self.open.pop();
+
+        let mut stmt = DoStmt::new(Box::new(Stmt::Block(block)), plus, token);
+        stmt.span = self.span_from(&stmt.token);
+        return Ok(Stmt::Do(stmt));
+    }
+
+    fn index_stmt(&mut self) -> BoxResult<Stmt> {
+        let token = self.previous().clone();
+        if !self.open.iter().any(|open| open.token_type == TokenType::Do) {
+            return Err(Box::new(ExecError::new(ErrorType::IndexOutsideLoop, token)));
+        }
+        return Ok(Stmt::Index(IndexStmt::new(token)));
+    }
+
+    /// recurse calls the word that is being defined
+    fn recurse_stmt(&mut self) -> BoxResult<Stmt> {
+        let mut token = self.previous().clone();
+        token.lexeme = match &self.defining {
+            Some((name, DefineMode::Regular)) => name.lexeme.clone(),
+            _ => return Err(Box::new(ExecError::new(ErrorType::RecurseOutsideWord, token)))
+        };
+        token.token_type = TokenType::Word;
+        return Ok(Stmt::Expr(ExprStmt::new(Expr::Word(WordExpr::new(token)))));
+    }
+
+    /// exit leaves a regular word or a constant.
+    /// do loops may keep their index on the return stack
+    fn exit_stmt(&mut self) -> BoxResult<Stmt> {
+        let token = self.previous().clone();
+        let in_loop = self.open.iter().any(|open| open.token_type == TokenType::Do);
+        match &self.defining {
+            Some((name, DefineMode::Regular)) | Some((name, DefineMode::Constant)) if !in_loop => {
+                Ok(Stmt::Exit(ExitStmt::new(name.clone(), token)))
+            },
+            _ => Err(Box::new(ExecError::new(ErrorType::InvalidExit, token)))
+        }
     }
 
     fn tick_stmt(&mut self) -> BoxResult<Stmt> {
@@ -249,6 +762,11 @@ impl Parser {
         return self.previous();
     }
 
+    /// the span from the start token to the previous token
+    fn span_from(&self, start: &Token) -> Span {
+        start.span.merge(&self.previous().span)
+    }
+
     fn previous(&self) -> &Token {
         return &self.tokens[self.current-1];
     }
@@ -261,10 +779,33 @@ impl Parser {
         &self.tokens[self.current]
     }
 
+    /// definitions and directives that can only be used at the top-level
+    fn is_boundary(token_type: TokenType) -> bool {
+        matches!(token_type,
+            TokenType::StartDefine
+                | TokenType::StartInlineDefine
+                | TokenType::StartConstDefine
+                | TokenType::Import
+                | TokenType::Use
+                | TokenType::Mod
+                | TokenType::Peephole
+                | TokenType::Parsing
+                | TokenType::Macro
+                | TokenType::Let
+                | TokenType::Forward)
+    }
+
     // attemtps to revocer from error state
-    // to allow more than 1 error message per pass
+    // to allow more than 1 error message per pass.
+    // skips everything up to the next ; or the next definition or directive
     fn sync(&mut self) {
-        self.advance();
+        self.open.clear();
+        self.defining = None;
+        while !self.is_at_end() && !Self::is_boundary(self.peek().token_type) {
+            if self.advance().token_type == TokenType::EndDefine {
+                return;
+            }
+        }
     }
 }
 
@@ -272,6 +813,7 @@ impl Parser {
 mod tests {
     use super::*;
     use crate::object::*;
+    use crate::filesystem::MemoryFileSystem;
 
     /// this pretty much tests most of the parser!
     #[test]
@@ -279,45 +821,48 @@ mod tests {
         let mut parser = Parser::new(": word 1 + ;", "").unwrap();
         let stmts = parser.parse().unwrap();
 
-        assert_eq!(stmts, vec![
-            Stmt::Define(DefineStmt::new(
-                    Token::new(
-                        TokenType::Word,
-                        Object::Word("word".into()),
-                        "word",
-                        1,
-                        2,
-                        ""),
-                Box::new(Stmt::Block(BlockStmt::new(
-                    vec![
-                    Stmt::Expr(ExprStmt::new(Expr::Literal(LiteralExpr::new(Token::new(
-                                        TokenType::Number,
-                                        Object::Number(1),
-                                        "1",
-                                        1,
-                                        7,
-                                        ""
-                                ))))),
-                    Stmt::Expr(ExprStmt::new(Expr::Word(WordExpr::new(Token::new(
-                                        TokenType::Word,
-                                        Object::Word("+".into()),
-                                        "+",
-                                        1,
-                                        9,
-                                        ""
-                                ))))),
-                    ], Token::new(
-                        TokenType::EndDefine,
-                        Object::Word(";".into()),
-                        ";",
-                        1,
-                        11,
-                        ""
-                    )))),
-                DefineMode::Regular
-            ))
+        let mut block = BlockStmt::new(
+            vec![
+            Stmt::Expr(ExprStmt::new(Expr::Literal(LiteralExpr::new(Token::new(
+                                TokenType::Number,
+                                Object::Number(1),
+                                "1",
+                                1,
+                                7,
+                                ""
+                        ).with_span(Span::new(1, 8, 1, 9)))))),
+            Stmt::Expr(ExprStmt::new(Expr::Word(WordExpr::new(Token::new(
+                                TokenType::Word,
+                                Object::Word("+".into()),
+                                "+",
+                                1,
+                                9,
+                                ""
+                        ).with_span(Span::new(1, 10, 1, 11)))))),
+            ], Token::new(
+                TokenType::EndDefine,
+                Object::Word(";".into()),
+                ";",
+                1,
+                11,
+                ""
+            ).with_span(Span::new(1, 12, 1, 13)));
+        block.span = Span::new(1, 8, 1, 13);
 
-        ]);
+        let mut define = DefineStmt::new(
+            Token::new(
+                TokenType::Word,
+                Object::Word("word".into()),
+                "word",
+                1,
+                2,
+                "").with_span(Span::new(1, 3, 1, 7)),
+            Box::new(Stmt::Block(block)),
+            DefineMode::Regular
+        );
+        define.span = Span::new(1, 1, 1, 13);
+
+        assert_eq!(stmts, vec![Stmt::Define(define)]);
     }
 
     #[test]
@@ -334,7 +879,7 @@ mod tests {
                             ":i16",
                             1,
                             0,
-                            ""),
+                            "").with_span(Span::new(1, 1, 1, 5)),
                         Box::new(Expr::Literal(LiteralExpr::new(
                                     Token::new(
                                         TokenType::Number,
@@ -342,11 +887,12 @@ mod tests {
                                         "257",
                                         1,
                                         5,
-                                        "")
+                                        "").with_span(Span::new(1, 6, 1, 9))
                         ))
                     )))),
                 )
         ]);
+        assert_eq!(stmts[0].span(), Span::new(1, 1, 1, 9));
     }
 
     #[test]
@@ -354,29 +900,279 @@ mod tests {
         let mut parser = Parser::new(":use module w1 w2 ;", "").unwrap();
         let stmts = parser.parse().unwrap();
 
-        assert_eq!(stmts, vec![
-            Stmt::Use(UseStmt::new(
-                Token::new(TokenType::Word,
-                    Object::Word("module".into()),
-                    "module",
-                    1,
-                    5,
-                    ""),
-                vec![
-                Token::new(TokenType::Word,
-                    Object::Word("w1".into()),
-                    "w1",
-                    1,
-                    12,
-                    ""),
-                Token::new(TokenType::Word,
-                    Object::Word("w2".into()),
-                    "w2",
-                    1,
-                    15,
-                    "")]
-                ))
-        ]);
+        let mut stmt = UseStmt::new(
+            Token::new(TokenType::Word,
+                Object::Word("module".into()),
+                "module",
+                1,
+                5,
+                "").with_span(Span::new(1, 6, 1, 12)),
+            vec![
+            Token::new(TokenType::Word,
+                Object::Word("w1".into()),
+                "w1",
+                1,
+                12,
+                "").with_span(Span::new(1, 13, 1, 15)),
+            Token::new(TokenType::Word,
+                Object::Word("w2".into()),
+                "w2",
+                1,
+                15,
+                "").with_span(Span::new(1, 16, 1, 18))]
+            );
+        stmt.span = Span::new(1, 1, 1, 20);
+
+        assert_eq!(stmts, vec![Stmt::Use(stmt)]);
+    }
+
+    #[test]
+    pub fn it_should_span_statements() {
+        let mut parser = Parser::new("1 if\n  2 else 3 then loop ' x until", "").unwrap();
+        let stmts = parser.parse().unwrap();
+
+        assert_eq!(stmts[1].token().lexeme, "if");
+        assert_eq!(stmts[1].span(), Span::new(1, 3, 2, 16));
+        match &stmts[1] {
+            Stmt::If(if_stmt) => {
+                assert_eq!(if_stmt.then_block.span(), Span::new(2, 3, 2, 4));
+                assert_eq!(if_stmt.else_block.as_ref().unwrap().span(), Span::new(2, 10, 2, 16));
+            },
+            _ => panic!("Expected if")
+        }
+
+        assert_eq!(stmts[2].token().lexeme, "loop");
+        assert_eq!(stmts[2].span(), Span::new(2, 17, 2, 31));
+        match &stmts[2] {
+            Stmt::Loop(loop_stmt) => match &*loop_stmt.block {
+                Stmt::Block(block) => assert_eq!(block.body[0].span(), Span::new(2, 22, 2, 25)),
+                _ => panic!("Expected block")
+            },
+            _ => panic!("Expected loop")
+        }
+    }
+
+    #[test]
+    pub fn it_should_parse_attributes() {
+        let mut parser = Parser::new(": nmi :entry rti ;", "").unwrap();
+        let stmts = parser.parse().unwrap();
+
+        match &stmts[0] {
+            Stmt::Define(define) => {
+                assert!(define.has_attribute(TokenType::Entry));
+                assert_eq!(define.name.lexeme, "nmi");
+            },
+            _ => panic!("Expected define")
+        }
+    }
+
+    #[test]
+    pub fn it_should_parse_effects() {
+        let mut parser = Parser::new(":i + :effect 2 1 :asm \"add\" ;", "").unwrap();
+        let stmts = parser.parse().unwrap();
+
+        match &stmts[0] {
+            Stmt::Define(define) => assert_eq!(define.effect, Some(StackEffect::new(2, 1))),
+            _ => panic!("Expected define")
+        }
+
+        assert!(Parser::new(": x :effect 1 ;", "").unwrap().parse().is_err());
+    }
+
+    #[test]
+    pub fn it_should_parse_stack_comments() {
+        let mut parser = Parser::new("( a -- ) : a ( ( x ) -- y ) 1 ( a -- b c ) ; : b :effect 2 0 ( a -- b ) ;
+            : c ( no effect ) ( a b -- c ) + ; ( a -- b ) : d ;", "").unwrap();
+        let stmts = parser.parse().unwrap();
+
+        let effects: Vec<Option<StackEffect>> = stmts.iter().map(|stmt| match stmt {
+            Stmt::Define(define) => define.effect,
+            _ => panic!("Expected define")
+        }).collect();
+        assert_eq!(effects, vec![
+            Some(StackEffect::new(0, 1)),
+            Some(StackEffect::new(2, 0)),
+            Some(StackEffect::new(2, 1)),
+            Some(StackEffect::new(1, 1))]);
+    }
+
+    #[test]
+    pub fn it_should_parse_peephole() {
+        let mut parser = Parser::new(":peephole push pull ;", "").unwrap();
+        let stmts = parser.parse().unwrap();
+
+        let mut stmt = PeepholeStmt::new(
+            Token::new(TokenType::Word,
+                Object::Word("push".into()),
+                "push",
+                1,
+                10,
+                "").with_span(Span::new(1, 11, 1, 15)),
+            Token::new(TokenType::Word,
+                Object::Word("pull".into()),
+                "pull",
+                1,
+                15,
+                "").with_span(Span::new(1, 16, 1, 20)),
+            vec![],
+            Token::new(TokenType::Peephole,
+                Object::Word(":peephole".into()),
+                ":peephole",
+                1,
+                0,
+                "").with_span(Span::new(1, 1, 1, 10))
+            );
+        stmt.span = Span::new(1, 1, 1, 22);
+
+        assert_eq!(stmts, vec![Stmt::Peephole(stmt)]);
+    }
+
+    #[test]
+    pub fn it_should_parse_do_loops() {
+        let mut parser = Parser::new("10 0 do i loop 10 0 do begin 1 until i 2 +loop", "").unwrap();
+        let stmts = parser.parse().unwrap();
+
+        let loops: Vec<(bool, usize)> = stmts.iter().filter_map(|stmt| match stmt {
+            Stmt::Do(do_stmt) => match &*do_stmt.block {
+                Stmt::Block(block) => Some((do_stmt.plus, block.body.len())),
+                _ => panic!("Expected block")
+            },
+            _ => None
+        }).collect();
+        assert_eq!(loops, vec![(false, 1), (true, 3)]);
+        assert_eq!(stmts[2].span(), Span::new(1, 6, 1, 15));
+    }
+
+    #[test]
+    pub fn it_should_fail_when_index_is_outside_of_do() {
+        let mut parser = Parser::new(": word i ; 1 0 do loop i", "").unwrap();
+        let errors = parser.parse().unwrap_err().errors;
+
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec![
+            "type: IndexOutsideLoop; lexeme: i".to_string(),
+            "type: IndexOutsideLoop; lexeme: i".to_string()]);
+    }
+
+    #[test]
+    pub fn it_should_fail_when_unterminated_do() {
+        let mut parser = Parser::new("1 0 do i", "").unwrap();
+        let errors = parser.parse().unwrap_err().errors;
+
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec!["type: UnterminatedBlock; lexeme: i".to_string()]);
+    }
+
+    #[test]
+    pub fn it_should_parse_begin_loops() {
+        let mut parser = Parser::new("begin 1 until begin dup while 1 - repeat", "").unwrap();
+        let stmts = parser.parse().unwrap();
+
+        assert!(matches!(stmts[0], Stmt::Loop(_)));
+        match &stmts[1] {
+            Stmt::While(while_stmt) => {
+                assert_eq!(while_stmt.token.lexeme, "begin");
+                assert_eq!(while_stmt.condition.span(), Span::new(1, 21, 1, 24));
+                assert_eq!(while_stmt.block.span(), Span::new(1, 31, 1, 41));
+            },
+            _ => panic!("Expected while")
+        }
+        assert_eq!(stmts[1].span(), Span::new(1, 15, 1, 41));
+    }
+
+    #[test]
+    pub fn it_should_fail_when_unterminated_while() {
+        let mut parser = Parser::new("begin dup while 1 -", "").unwrap();
+        let errors = parser.parse().unwrap_err().errors;
+
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec!["type: UnterminatedBlock; lexeme: -".to_string()]);
+    }
+
+    #[test]
+    pub fn it_should_parse_case() {
+        let mut parser = Parser::new("x case 1 of a endof 2 1 + of b c endof d endcase", "").unwrap();
+        let stmts = parser.parse().unwrap();
+
+        let block_len = |stmt: &Stmt| match stmt {
+            Stmt::Block(block) => block.body.len(),
+            _ => panic!("Expected block")
+        };
+        match &stmts[1] {
+            Stmt::Case(case_stmt) => {
+                let branches: Vec<(usize, usize)> = case_stmt.branches.iter()
+                    .map(|branch| (block_len(&branch.value), block_len(&branch.block)))
+                    .collect();
+                assert_eq!(branches, vec![(1, 1), (3, 2)]);
+                assert_eq!(block_len(&case_stmt.default), 1);
+            },
+            _ => panic!("Expected case")
+        }
+        assert_eq!(stmts[1].span(), Span::new(1, 3, 1, 49));
+    }
+
+    #[test]
+    pub fn it_should_fail_when_unterminated_case() {
+        let mut parser = Parser::new("1 case 1 of 2 endof 3", "").unwrap();
+        let errors = parser.parse().unwrap_err().errors;
+
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec!["type: UnterminatedBlock; lexeme: 3".to_string()]);
+    }
+
+    #[test]
+    pub fn it_should_parse_let() {
+        let mut parser = Parser::new(":let counter :i16", "").unwrap();
+        let stmts = parser.parse().unwrap();
+
+        match &stmts[0] {
+            Stmt::Let(let_stmt) => {
+                assert_eq!(let_stmt.name.lexeme, "counter");
+                assert_eq!(let_stmt.width_name(), "i16");
+                assert_eq!(let_stmt.size(), 2);
+            },
+            _ => panic!("Expected let")
+        }
+        assert_eq!(stmts[0].span(), Span::new(1, 1, 1, 18));
+    }
+
+    #[test]
+    pub fn it_should_fail_when_let_has_no_width() {
+        let mut parser = Parser::new(":let counter 1", "").unwrap();
+        let errors = parser.parse().unwrap_err().errors;
+
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec!["type: UnexpectedToken; lexeme: 1".to_string()]);
+    }
+
+    fn error_ids(source: &str) -> Vec<String> {
+        let mut parser = Parser::new(source, "").unwrap();
+        let errors = parser.parse().unwrap_err().errors;
+        errors.iter().map(|x| format!("{:?}", x)).collect()
+    }
+
+    #[test]
+    pub fn it_should_recover_at_the_next_definition() {
+        assert_eq!(error_ids(": a 1 + : b 2 ; : c then ; : d 3 ;"), vec![
+            "type: UnterminatedBlock; lexeme: a".to_string(),
+            "type: UnexpectedToken; lexeme: then".to_string()]);
+    }
+
+    #[test]
+    pub fn it_should_report_unclosed_blocks() {
+        assert_eq!(error_ids(": a 1 if 2 ; : b 1 ; : c begin 1 :let x :i8 : d 10 0 do case ; 1"), vec![
+            "type: UnterminatedBlock; lexeme: if".to_string(),
+            "type: UnterminatedBlock; lexeme: begin".to_string(),
+            "type: UnterminatedBlock; lexeme: case".to_string()]);
+    }
+
+    #[test]
+    pub fn it_should_skip_to_the_next_end_of_definition() {
+        assert_eq!(error_ids(":use 1 2 ; : a else 1 2 ; then 3 ; : b i ; 4"), vec![
+            "type: UnexpectedToken; lexeme: :use".to_string(),
+            "type: UnexpectedToken; lexeme: else".to_string(),
+            "type: UnexpectedToken; lexeme: then".to_string(),
+            "type: IndexOutsideLoop; lexeme: i".to_string()]);
     }
 
     #[test]
@@ -424,4 +1220,153 @@ mod tests {
         assert_eq!(errors_id, vec!["type: UnterminatedBlock; lexeme: 1".to_string()]);
     }
 
+    #[test]
+    pub fn it_should_expand_parsing_words() {
+        let mut parser = Parser::new(":parsing byte 1 \".byte \" swap concat emit_asm ;
+            :parsing equ 2 emit_const ;
+            byte 5 equ answer 42", "").unwrap();
+        let stmts = parser.parse().unwrap();
+
+        let kinds: Vec<String> = stmts.iter().map(|stmt| match stmt {
+            Stmt::Asm(asm) => asm.code.to_string(),
+            Stmt::Define(define) => format!("{} {:?}", define.name.lexeme, define.mode),
+            _ => panic!("Unexpected statement")
+        }).collect();
+        assert_eq!(kinds, vec![".byte 5".to_string(), "answer Constant".to_string()]);
+    }
+
+    fn parse_with_files(source: &str, files: Vec<(&str, &str)>) -> Result<Vec<Stmt>, ErrorList> {
+        Parser::with_filesystem(source, "main.fth", Rc::new(MemoryFileSystem::with(files)))?.parse()
+    }
+
+    #[test]
+    pub fn it_should_import_parsing_words_from_filesystem() {
+        let stmts = parse_with_files(":import \"lib/words.fth\" call_it main", vec![
+            ("lib/words.fth", ":import \"byte.fth\""),
+            ("lib/byte.fth", ":parsing call_it 1 emit_word ;")
+        ]).unwrap();
+
+        assert_eq!(stmts.len(), 2);
+        assert_eq!(stmts[1].token().lexeme, "main");
+        // the compiler does not parse the file again
+        match &stmts[0] {
+            Stmt::Import(import) => assert_eq!(import.module.as_ref().unwrap().source, ":import \"byte.fth\""),
+            _ => panic!("Expected import")
+        }
+    }
+
+    #[test]
+    pub fn it_should_fail_when_parsing_word_is_missing_tokens() {
+        let mut parser = Parser::new(":parsing byte 1 emit_asm ; byte", "").unwrap();
+        let errors = parser.parse().unwrap_err().errors;
+
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec!["type: UnexpectedToken; lexeme: ".to_string()]);
+    }
+
+
+    #[test]
+    pub fn it_should_parse_recurse_as_call_of_the_word() {
+        let mut parser = Parser::new(": down recurse ;", "").unwrap();
+        let stmts = parser.parse().unwrap();
+
+        match &stmts[0] {
+            Stmt::Define(define) => match &*define.body {
+                Stmt::Block(block) => match &block.body[0] {
+                    Stmt::Expr(ExprStmt { expr: Expr::Word(word) }) => assert_eq!(word.name.lexeme, "down"),
+                    _ => panic!("Expected word")
+                },
+                _ => panic!("Expected block")
+            },
+            _ => panic!("Expected define")
+        }
+
+        assert_eq!(error_ids("recurse :i up recurse ; : ok 1 ;"), vec![
+            "type: RecurseOutsideWord; lexeme: recurse".to_string(),
+            "type: RecurseOutsideWord; lexeme: recurse".to_string()
+        ]);
+    }
+
+    #[test]
+    pub fn it_should_parse_forward() {
+        let mut parser = Parser::new(":forward word ( a -- b ) ;", "").unwrap();
+        let stmts = parser.parse().unwrap();
+
+        match &stmts[0] {
+            Stmt::Forward(forward) => {
+                assert_eq!(forward.name.lexeme, "word");
+                assert_eq!(forward.effect, Some(StackEffect::new(1, 1)));
+            },
+            _ => panic!("Expected forward")
+        }
+        assert_eq!(stmts[0].span(), Span::new(1, 1, 1, 27));
+    }
+
+    #[test]
+    pub fn it_should_only_exit_regular_words_and_constants() {
+        let mut parser = Parser::new(": a exit ; :c b exit ;", "").unwrap();
+        let stmts = parser.parse().unwrap();
+        assert_eq!(stmts.len(), 2);
+
+        assert_eq!(error_ids(":i b exit ; exit : c 1 2 do exit loop ; : d exit ;"), vec![
+            "type: InvalidExit; lexeme: exit".to_string(),
+            "type: InvalidExit; lexeme: exit".to_string(),
+            "type: InvalidExit; lexeme: exit".to_string()
+        ]);
+    }
+
+    #[test]
+    pub fn it_should_parse_conditional_compilation() {
+        let mut parser = Parser::new(":if NES 1 - ; : a 1 ; : b 2 ; :else 3 :endif : c :ifdef PAL 4 :endif ;", "").unwrap();
+        let stmts = parser.parse().unwrap();
+        assert_eq!(stmts.len(), 2);
+
+        match &stmts[0] {
+            Stmt::Cond(cond) => {
+                match &cond.condition {
+                    Condition::Expr(condition) => assert_eq!(condition.span(), Span::new(1, 5, 1, 14)),
+                    _ => panic!("Expected expression")
+                }
+                assert_eq!(cond.then_branch.len(), 2);
+                assert_eq!(cond.else_branch.len(), 1);
+            },
+            _ => panic!("Expected conditional compilation")
+        }
+        assert_eq!(stmts[0].span(), Span::new(1, 1, 1, 45));
+
+        // blocks can not end inside of a branch
+        assert_eq!(error_ids(":ifdef NES : a 1 :else : a 2 ; :endif"), vec![
+            "type: UnterminatedBlock; lexeme: a".to_string()
+        ]);
+        assert_eq!(error_ids(":ifdef NES 1"), vec!["type: UnterminatedBlock; lexeme: 1".to_string()]);
+    }
+
+    #[test]
+    pub fn it_should_expand_macros() {
+        let mut parser = Parser::new(":macro twice body ; body body ; : a twice [ 1 + ] twice dup ;", "").unwrap();
+        let stmts = parser.parse().unwrap();
+        assert_eq!(stmts.len(), 1);
+
+        match &stmts[0] {
+            Stmt::Define(define) => match &*define.body {
+                Stmt::Block(block) => {
+                    let lexemes: Vec<Vec<String>> = block.body.iter().map(|stmt| match stmt {
+                        Stmt::Expand(expand) => expand.body.iter().map(|stmt| stmt.token().lexeme).collect(),
+                        _ => panic!("Expected expansion")
+                    }).collect();
+                    assert_eq!(lexemes, vec![vec!["1", "+", "1", "+"], vec!["dup", "dup"]]);
+                    assert_eq!(block.body[0].span(), Span::new(1, 37, 1, 50));
+                },
+                _ => panic!("Expected block")
+            },
+            _ => panic!("Expected define")
+        }
+
+        assert_eq!(error_ids(":macro twice body ; body body ; : a twice ;"), vec![
+            "type: UnexpectedToken; lexeme: ;".to_string()
+        ]);
+        assert_eq!(error_ids(":macro twice body ; body body ; : a twice [ 1 ; : b 2 ;"), vec![
+            "type: UnterminatedBlock; lexeme: [".to_string()
+        ]);
+    }
 }
@@ -0,0 +1,181 @@
+use super::stmt::Compiled;
+
+/// a rewrite rule declared with :peephole
+/// whenever the expansion of the first template word is directly
+/// followed by the expansion of the second template word the pair is
+/// replaced by the replacement.
+/// the compiler does not know what the text means, it only
+/// matches the template output byte by byte.
+/// a match has to start and end at a line boundary so that
+/// a rule never matches part of an instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeepholeRule {
+    pub first: Vec<u8>,
+    pub second: Vec<u8>,
+    pub replacement: Vec<u8>
+}
+
+impl PeepholeRule {
+    pub fn new(first: Vec<u8>, second: Vec<u8>, replacement: Vec<u8>) -> Self {
+        Self {
+            first,
+            second,
+            replacement
+        }
+    }
+
+    fn pattern(&self) -> Vec<u8> {
+        let mut pattern = self.first.clone();
+        pattern.extend_from_slice(&self.second);
+        pattern
+    }
+
+    /// fragments are flattened into separate lines,
+    /// so their start and end are line boundaries too
+    fn is_line_start(data: &[u8], i: usize) -> bool {
+        i == 0 || data[i - 1] == b'\n'
+    }
+
+    fn is_line_end(data: &[u8], i: usize) -> bool {
+        i == data.len() || data[i] == b'\n' || Self::is_line_start(data, i)
+    }
+
+    /// rewrites every occurrence inside of a single fragment
+    fn rewrite(&self, data: &mut Vec<u8>) -> bool {
+        let pattern = self.pattern();
+        let mut result = vec![];
+        let mut changed = false;
+
+        let mut i = 0;
+        while i < data.len() {
+            if data[i..].starts_with(&pattern)
+                && Self::is_line_start(data, i)
+                && Self::is_line_end(data, i + pattern.len()) {
+                result.extend_from_slice(&self.replacement);
+                i += pattern.len();
+                changed = true;
+            } else {
+                result.push(data[i]);
+                i += 1;
+            }
+        }
+
+        if changed {
+            *data = result;
+        }
+        changed
+    }
+
+    /// rewrites pairs that are split between two fragments
+    /// the replacement is moved into the first fragment
+    fn rewrite_boundaries(&self, output: &mut [Compiled]) -> bool {
+        let mut changed = false;
+        let mut prev: Option<usize> = None;
+
+        for i in 0..output.len() {
+            if output[i].data.is_empty() {
+                continue;
+            }
+
+            if let Some(p) = prev {
+                let end = output[p].data.len().saturating_sub(self.first.len());
+                if output[p].data.ends_with(&self.first)
+                    && output[i].data.starts_with(&self.second)
+                    && Self::is_line_start(&output[p].data, end)
+                    && Self::is_line_end(&output[i].data, self.second.len()) {
+                    output[p].data.truncate(end);
+                    output[p].data.extend_from_slice(&self.replacement);
+                    output[i].data.drain(..self.second.len());
+                    changed = true;
+                }
+            }
+
+            if !output[i].data.is_empty() {
+                prev = Some(i);
+            }
+        }
+
+        changed
+    }
+}
+
+pub struct Peephole;
+
+impl Peephole {
+    /// applies all rules in declaration order.
+    /// rules that shrink the code are applied until nothing changes
+    /// because removing a pair may create a new one
+    pub fn apply(rules: &[PeepholeRule], output: &mut [Compiled]) {
+        for rule in rules {
+            if rule.first.is_empty() || rule.second.is_empty() {
+                continue;
+            }
+
+            loop {
+                let mut changed = false;
+                for fragment in output.iter_mut() {
+                    changed |= rule.rewrite(&mut fragment.data);
+                }
+                changed |= rule.rewrite_boundaries(output);
+
+                if !changed || rule.replacement.len() >= rule.pattern().len() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragments(data: Vec<&str>) -> Vec<Compiled> {
+        data.iter().map(|d| Compiled::new(d.as_bytes().to_vec())).collect()
+    }
+
+    #[test]
+    fn it_should_rewrite_inside_fragment() {
+        let rules = vec![PeepholeRule::new(b"push\n".to_vec(), b"pull\n".to_vec(), vec![])];
+        let mut output = fragments(vec!["lda\npush\npull\nrts"]);
+        Peephole::apply(&rules, &mut output);
+
+        assert_eq!(Compiled::flatten(output).unwrap(), "lda\nrts\n");
+    }
+
+    #[test]
+    fn it_should_rewrite_across_fragments() {
+        let rules = vec![PeepholeRule::new(b"push\n".to_vec(), b"pull\n".to_vec(), b"nop\n".to_vec())];
+        let mut output = fragments(vec!["lda\npush\n", "", "pull\nadc"]);
+        Peephole::apply(&rules, &mut output);
+
+        assert_eq!(Compiled::flatten(output).unwrap(), "lda\nnop\n\nadc\n");
+    }
+
+    #[test]
+    fn it_should_rewrite_nested_pairs() {
+        let rules = vec![PeepholeRule::new(b"push\n".to_vec(), b"pull\n".to_vec(), vec![])];
+        let mut output = fragments(vec!["push\npush\npull\npull\nrts"]);
+        Peephole::apply(&rules, &mut output);
+
+        assert_eq!(Compiled::flatten(output).unwrap(), "rts\n");
+    }
+
+    #[test]
+    fn it_should_not_rewrite_separated_pairs() {
+        let rules = vec![PeepholeRule::new(b"push\n".to_vec(), b"pull\n".to_vec(), vec![])];
+        let mut output = fragments(vec!["push\nlabel:\npull\n"]);
+        Peephole::apply(&rules, &mut output);
+
+        assert_eq!(Compiled::flatten(output).unwrap(), "push\nlabel:\npull\n\n");
+    }
+
+    #[test]
+    fn it_should_only_match_whole_lines() {
+        let rules = vec![PeepholeRule::new(b"pha".to_vec(), b"\npla".to_vec(), vec![])];
+        let mut output = fragments(vec!["phax\npla", "tpha\npla\n", "pha", "\nplax", "pha\npla"]);
+        Peephole::apply(&rules, &mut output);
+
+        assert_eq!(Compiled::flatten(output).unwrap(), "phax\npla\ntpha\npla\n\npha\n\nplax\n");
+    }
+}
@@ -1,5 +1,5 @@
 use super::error::*;
-use super::token::{Token, TokenType};
+use super::token::{Token, TokenType, Span};
 use super::object::*;
 use std::collections::HashMap;
 
@@ -8,9 +8,17 @@ pub struct Scanner {
     path: String,
     keywords: HashMap<String, TokenType>,
 
+    // byte offsets into the source
     current: usize,
     start: usize,
-    line: usize
+    line: usize,
+    // radix of numbers without a prefix
+    radix: u32,
+    // offset of the first character of the current line
+    line_start: usize,
+    // position of the first character of the current token
+    start_line: usize,
+    start_column: usize
 }
 
 impl Scanner {
@@ -22,14 +30,39 @@ impl Scanner {
         keywords.insert("do".to_string(), TokenType::Do);
         keywords.insert("until".to_string(), TokenType::Until);
         keywords.insert("loop".to_string(), TokenType::Loop);
+        keywords.insert("+loop".to_string(), TokenType::PlusLoop);
+        keywords.insert("begin".to_string(), TokenType::Begin);
+        keywords.insert("while".to_string(), TokenType::While);
+        keywords.insert("repeat".to_string(), TokenType::Repeat);
+        keywords.insert("case".to_string(), TokenType::Case);
+        keywords.insert("of".to_string(), TokenType::Of);
+        keywords.insert("endof".to_string(), TokenType::EndOf);
+        keywords.insert("endcase".to_string(), TokenType::EndCase);
+        keywords.insert("i".to_string(), TokenType::Index);
+        keywords.insert("recurse".to_string(), TokenType::Recurse);
+        keywords.insert("exit".to_string(), TokenType::Exit);
         keywords.insert(":".to_string(), TokenType::StartDefine);
         keywords.insert(";".to_string(), TokenType::EndDefine);
         keywords.insert(":i".to_string(), TokenType::StartInlineDefine);
         keywords.insert(":c".to_string(), TokenType::StartConstDefine);
+        keywords.insert(":let".to_string(), TokenType::Let);
+        keywords.insert(":forward".to_string(), TokenType::Forward);
+        keywords.insert(":if".to_string(), TokenType::CondIf);
+        keywords.insert(":ifdef".to_string(), TokenType::CondIfDef);
+        keywords.insert(":else".to_string(), TokenType::CondElse);
+        keywords.insert(":endif".to_string(), TokenType::CondEndIf);
         keywords.insert(":asm".to_string(), TokenType::Asm);
         keywords.insert(":import".to_string(), TokenType::Import);
         keywords.insert(":use".to_string(), TokenType::Use);
         keywords.insert(":mod".to_string(), TokenType::Mod);
+        keywords.insert(":peephole".to_string(), TokenType::Peephole);
+        keywords.insert(":parsing".to_string(), TokenType::Parsing);
+        keywords.insert(":macro".to_string(), TokenType::Macro);
+        keywords.insert("[".to_string(), TokenType::StartQuote);
+        keywords.insert("]".to_string(), TokenType::EndQuote);
+        keywords.insert(":entry".to_string(), TokenType::Entry);
+        keywords.insert(":noinline".to_string(), TokenType::NoInline);
+        keywords.insert(":effect".to_string(), TokenType::Effect);
 
         keywords.insert(":i8".to_string(), TokenType::I8);
         keywords.insert(":i16".to_string(), TokenType::I16);
@@ -45,7 +78,11 @@ impl Scanner {
 
             current: 0,
             start: 0,
-            line: 1
+            line: 1,
+            radix: 10,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1
         }
     }
 
@@ -59,15 +96,9 @@ impl Scanner {
                 Err(err) => errors.push(err)
             }
         }
-        self.start = self.current;
+        self.begin_token();
         // insert eof token
-        tokens.push(Token::new(
-            TokenType::EndOfFile,
-            Object::Nil,
-            "",
-            self.line,
-            self.start,
-            &self.path));
+        tokens.push(self.token(TokenType::EndOfFile, Object::Nil, ""));
 
         if errors.len() > 0 {
             return Err(ErrorList::new(errors));
@@ -79,16 +110,13 @@ impl Scanner {
     /// Returns either an error
     /// a token or none if the character is ignored
     fn scan_token(&mut self) -> BoxResult<Option<Token>> {
-        self.start = self.current;
+        self.begin_token();
         let c = self.advance();
 
         let token = match c {
-            ' ' | '\r' | '(' | ')' => return Ok(None),
-            '\n' => {
-                self.line += 1;
-                return Ok(None);
-            },
-            '#' => {
+            ' ' | '\r' | '\n' => return Ok(None),
+            '(' => self.scan_comment()?,
+            '#' | '\\' => {
                 // comment until end of line
                 while self.peek() != '\n'
                     && !self.is_at_end() {
@@ -96,6 +124,7 @@ impl Scanner {
                 }
                 return Ok(None);
             },
+            '{' if self.peek() == '{' => self.scan_raw_str()?,
             '"' => match self.scan_str(c) {
                 Ok(token) => token,
                 Err(err) => return Err(err)
@@ -111,7 +140,7 @@ impl Scanner {
             },
             _ => {
                 // TODO use pattern range in the future?
-                if Self::is_digit(c) {
+                if self.is_number_start(c) {
                     match self.scan_number(c) {
                         Ok(token) => token,
                         Err(err) => return Err(err)
@@ -125,6 +154,19 @@ impl Scanner {
                         .to_string()
                         .clone();
 
+                    // :hex and :decimal change the radix for the rest of the file
+                    match word.as_ref() {
+                        ":hex" => {
+                            self.radix = 16;
+                            return Ok(None);
+                        },
+                        ":decimal" => {
+                            self.radix = 10;
+                            return Ok(None);
+                        },
+                        _ => {}
+                    }
+
                     let mut token_type = TokenType::Word;
 
                     // is it keyword?
@@ -132,24 +174,12 @@ impl Scanner {
                         token_type = self.keywords[&word];
                     }
 
-                    Token::new(
-                        token_type,
-                        Object::Word(word.clone()),
-                        &word,
-                        self.line,
-                        self.start,
-                        &self.path)
+                    self.token(token_type, Object::Word(word.clone()), &word)
                 } else {
                     return Err(Box::new(
                             ExecError::new(
                                 ErrorType::InvalidToken,
-                                Token::new(
-                                    TokenType::Invalid,
-                                    Object::Nil,
-                                    "",
-                                    self.line,
-                                    self.start,
-                                    &self.path))));
+                                self.token(TokenType::Invalid, Object::Nil, ""))));
                 }
             }
         };
@@ -158,13 +188,43 @@ impl Scanner {
     }
 
     fn scan_tick(&mut self) -> BoxResult<Token> {
-        return Ok(Token::new(
-                TokenType::Tick,
-                Object::Nil,
-                &self.source[self.start..self.current].to_string(),
-                self.line,
-                self.start,
-                &self.path));
+        let lexeme = self.source[self.start..self.current].to_string();
+        return Ok(self.token(TokenType::Tick, Object::Nil, &lexeme));
+    }
+
+    /// ( ... ) comments may be nested.
+    /// the text is kept because it may declare a stack effect
+    fn scan_comment(&mut self) -> BoxResult<Token> {
+        // ( has to be followed by a space just like in other forths.
+        // (12) used to group values before parens were comments
+        let grouping = !Self::is_ignored(self.peek()) && self.peek() != ')';
+
+        let mut depth = 1;
+        while depth > 0 && !self.is_at_end() {
+            match self.advance() {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+        }
+
+        let lexeme = self.source[self.start..self.current].to_string();
+        if depth > 0 {
+            return Err(Box::new(
+                    ExecError::new(
+                        ErrorType::UnterminatedComment,
+                        self.token(TokenType::Invalid, Object::Nil, ""))));
+        }
+
+        if grouping {
+            return Err(Box::new(
+                    ExecError::new(
+                        ErrorType::ParenGrouping,
+                        self.token(TokenType::Invalid, Object::Nil, &lexeme))));
+        }
+
+        let text = self.source[self.start+1..self.current-1].to_string();
+        return Ok(self.token(TokenType::Comment, Object::Str(text), &lexeme));
     }
 
     fn scan_char(&mut self, quote: char) -> BoxResult<Token> {
@@ -188,30 +248,15 @@ impl Scanner {
                 return Err(Box::new(
                         ExecError::new(
                             ErrorType::UnterminatedString,
-                            Token::new(
-                                TokenType::Invalid,
-                                Object::Nil,
-                                "",
-                                self.line,
-                                self.start,
-                                &self.path))));
+                            self.token(TokenType::Invalid, Object::Nil, ""))));
             }
-            return Ok(Token::new(
-                    TokenType::Number,
-                    Object::Number(c as ObjNumber),
-                    &self.source[self.start..self.current].to_string(),
-                    self.line,
-                    self.start,
-                    &self.path));
+            let lexeme = self.source[self.start..self.current].to_string();
+            return Ok(self.token(TokenType::Number, Object::Number(c as ObjNumber), &lexeme));
         }
     }
 
     fn scan_str(&mut self, quote: char) -> BoxResult<Token> {
         while self.peek() != quote && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
-            }
-
             // escape
             if self.peek() == '\\' {
                 self.advance();
@@ -223,13 +268,7 @@ impl Scanner {
             return Err(Box::new(
                     ExecError::new(
                         ErrorType::UnterminatedString,
-                        Token::new(
-                            TokenType::Invalid,
-                            Object::Nil,
-                            "",
-                            self.line,
-                            self.start,
-                            &self.path))));
+                        self.token(TokenType::Invalid, Object::Nil, ""))));
         }
 
         // closing quote
@@ -238,45 +277,91 @@ impl Scanner {
         let unescaped = Scanner::unescape(self.source[self.start+1..self.current-1].to_string());
         let lexeme = Scanner::unescape(self.source[self.start..self.current].to_string());
 
-        return Ok(Token::new(
-                TokenType::Str,
-                Object::Str(unescaped.clone()),
-                &lexeme,
-                self.line,
-                self.start,
-                &self.path));
+        return Ok(self.token(TokenType::Str, Object::Str(unescaped.clone()), &lexeme));
     }
 
-    fn scan_number(&mut self, c: char) -> BoxResult<Token> {
-        // decide if it is hex, binary or decimal
-        if c == '0' && self.is_match('x') {
-            self.scan_hex(c)
-        } else if c == '0' && self.is_match('b') {
-            self.scan_bin(c)
-        } else {
-            self.scan_dec(c)
-        }
+    /// numbers start with a digit, $ (hex), % (binary) or a - sign.
+    /// in hex mode they may also start with a hex letter
+    fn is_number_start(&self, c: char) -> bool {
+        let prefixed = |prefix: char, next: char| {
+            (prefix == '$' && Self::is_hex(next)) || (prefix == '%' && Self::is_binary(next))
+        };
+
+        Self::is_digit(c)
+            || prefixed(c, self.peek())
+            || self.is_hex_number(self.start)
+            || (c == '-' && (Self::is_digit(self.peek())
+                             || prefixed(self.peek(), self.peek_next())
+                             || self.is_hex_number(self.current)))
+    }
+
+    /// in hex mode a word made only of hex digits is a number
+    fn is_hex_number(&self, start: usize) -> bool {
+        let mut chars = self.source[start..].chars()
+            .take_while(|&c| !Self::is_ignored(c) && c != '\0');
+
+        self.radix == 16
+            && chars.next().is_some_and(Self::is_hex)
+            && chars.all(|c| Self::is_hex(c) || c == '_')
     }
 
-    fn scan_hex(&mut self, _c: char) -> BoxResult<Token> {
-        while Scanner::is_hex(self.peek()) {
+    /// {{ ... }} is a string without escapes.
+    /// more braces can be used if the text contains }}
+    fn scan_raw_str(&mut self) -> BoxResult<Token> {
+        let mut braces = 1;
+        while self.is_match('{') {
+            braces += 1;
+        }
+        let delim = "}".repeat(braces);
+
+        let text_start = self.current;
+        while !self.source[..self.current].ends_with(&delim) || self.current - text_start < braces {
+            if self.is_at_end() {
+                return Err(Box::new(
+                        ExecError::new(
+                            ErrorType::UnterminatedString,
+                            self.token(TokenType::Invalid, Object::Nil, ""))));
+            }
             self.advance();
         }
-        return self.get_num("0x", 2, TokenType::Number, 16);
+
+        let text = Scanner::trim_indent(&self.source[text_start..self.current-braces]);
+        let lexeme = self.source[self.start..self.current].to_string();
+        return Ok(self.token(TokenType::Str, Object::Str(text), &lexeme));
     }
 
-    fn scan_bin(&mut self, _c: char) -> BoxResult<Token> {
-        while Scanner::is_binary(self.peek()) {
-            self.advance();
+    fn scan_number(&mut self, c: char) -> BoxResult<Token> {
+        // the sign is part of the literal
+        let (c, sign) = if c == '-' {
+            (self.advance(), 1)
+        } else {
+            (c, 0)
+        };
+
+        // a prefix always decides the radix,
+        // otherwise the radix set by :hex or :decimal is used.
+        // 0b is a hex number in hex mode
+        if c == '0' && self.is_match('x') {
+            self.get_num(sign + 2, TokenType::Number, 16)
+        } else if c == '$' {
+            self.get_num(sign + 1, TokenType::Number, 16)
+        } else if c == '0' && self.radix != 16 && self.is_match('b') {
+            self.get_num(sign + 2, TokenType::Number, 2)
+        } else if c == '%' {
+            self.get_num(sign + 1, TokenType::Number, 2)
+        } else if c == '0' && self.is_match('o') {
+            self.get_num(sign + 2, TokenType::Number, 8)
+        } else if self.radix != 10 {
+            self.get_num(sign, TokenType::Number, self.radix)
+        } else {
+            self.scan_dec(sign)
         }
-        return self.get_num("0b", 2, TokenType::Number, 2);
     }
 
-    fn scan_dec(&mut self, _c: char) -> BoxResult<Token> {
-        let start_offset = 0;
+    fn scan_dec(&mut self, start_offset: usize) -> BoxResult<Token> {
         let mut token_type = TokenType::Number;
         // decimal
-        while Scanner::is_digit(self.peek()) {
+        while Scanner::is_digit(self.peek()) || self.peek() == '_' {
             self.advance();
         }
 
@@ -284,25 +369,26 @@ impl Scanner {
         if self.peek() == '.' && Scanner::is_digit(self.peek_next()) {
             self.advance();
             token_type = TokenType::Real;
-            while Scanner::is_digit(self.peek()) {
+            while Scanner::is_digit(self.peek()) || self.peek() == '_' {
                 self.advance();
             }
         }
 
-        return self.get_num("", start_offset, token_type, 10);
+        return self.get_num(start_offset, token_type, 10);
     }
 
-    fn get_num(&mut self, prefix: &str, start_offset: usize, token_type: TokenType, radix: u32) -> BoxResult<Token> {
+    /// start_offset is the length of the sign and prefix.
+    /// _ may be used to separate digits
+    fn get_num(&mut self, start_offset: usize, token_type: TokenType, radix: u32) -> BoxResult<Token> {
         // advance to next space, tab or new line
         while !Self::is_ignored(self.peek())
             && !self.is_at_end() {
             self.advance();
         }
 
-        let number = self.source[self.start+start_offset..self.current].to_string().clone();
-
-        let mut lexeme = prefix.to_string();
-        lexeme.push_str(&number);
+        let lexeme = self.source[self.start..self.current].to_string();
+        let number = self.source[self.start+start_offset..self.current].replace('_', "");
+        let negative = lexeme.starts_with('-');
 
         if token_type == TokenType::Real {
             let num = match Scanner::str_to_real(&number) {
@@ -311,23 +397,12 @@ impl Scanner {
                     return Err(Box::new(
                             ExecError::new(
                                 ErrorType::NumberParseError,
-                                Token::new(
-                                    TokenType::Invalid,
-                                    Object::Nil,
-                                    &lexeme,
-                                    self.line,
-                                    self.start,
-                                    &self.path))));
+                                self.token(TokenType::Invalid, Object::Nil, &lexeme))));
                 }
             };
 
-            return Ok(Token::new(
-                    token_type,
-                    Object::Real(num),
-                    &lexeme,
-                    self.line,
-                    self.start,
-                    &self.path));
+            let num = if negative { -num } else { num };
+            return Ok(self.token(token_type, Object::Real(num), &lexeme));
         } else {
             let num = match Scanner::str_to_num(&number, radix) {
                 Some(n) => n,
@@ -335,34 +410,20 @@ impl Scanner {
                     return Err(Box::new(
                             ExecError::new(
                                 ErrorType::NumberParseError,
-                                Token::new(
-                                    TokenType::Invalid,
-                                    Object::Nil,
-                                    &lexeme,
-                                    self.line,
-                                    self.start,
-                                    &self.path)))); }
+                                self.token(TokenType::Invalid, Object::Nil, &lexeme)))); }
             };
 
-            return Ok(Token::new(
-                    token_type,
-                    Object::Number(num),
-                    &lexeme,
-                    self.line,
-                    self.start,
-                    &self.path));
+            let num = if negative { -num } else { num };
+            return Ok(self.token(token_type, Object::Number(num), &lexeme));
         }
     }
 
     fn peek(&self) -> char {
-        self.source.chars().nth(self.current).unwrap_or('\0')
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            return '\0';
-        }
-        return self.source.chars().nth(self.current + 1).unwrap_or('\0');
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
     fn is_match(&mut self, c: char) -> bool {
@@ -374,8 +435,34 @@ impl Scanner {
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.source.chars().nth(self.current-1).unwrap_or('\0')
+        if self.is_at_end() {
+            return '\0';
+        }
+        let c = self.peek();
+        self.current += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.line_start = self.current;
+        }
+        c
+    }
+
+    /// columns count characters, not bytes
+    fn column(&self) -> usize {
+        self.source[self.line_start..self.current].chars().count() + 1
+    }
+
+    fn begin_token(&mut self) {
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column();
+    }
+
+    /// creates a token that spans from the start of
+    /// the current token to the current character
+    fn token(&self, token_type: TokenType, literal: Object, lexeme: &str) -> Token {
+        Token::new(token_type, literal, lexeme, self.start_line, self.start, &self.path)
+            .with_span(Span::new(self.start_line, self.start_column, self.line, self.column()))
     }
 
     fn is_at_end(&mut self) -> bool {
@@ -386,13 +473,11 @@ impl Scanner {
         c == ' '
             || c == '\t'
             || c == '\n'
-            || c == '('
-            || c == ')'
     }
 
     fn is_alpha(c: char) -> bool {
-        return (c >= 'a' && c <= 'z') ||
-            (c >= 'A' && c <= 'Z')
+        // words may contain any unicode letter
+        return c.is_alphabetic()
             || c == '_'
             || c == '-'
             || c == '+'
@@ -404,8 +489,14 @@ impl Scanner {
             || c == '='
             || c == ':'
             || c == ';'
+            || c == '['
+            || c == ']'
             || c == '@'
-            || c == '!';
+            || c == '!'
+            || c == '&'
+            || c == '|'
+            || c == '^'
+            || c == '~';
     }
 
     fn is_digit(c: char) -> bool {
@@ -444,23 +535,47 @@ impl Scanner {
     fn unescape(input: String) -> String {
         let mut result = "".to_string();
 
-        let mut skip = false;
-        for i in 0..input.len() {
-            if skip {
-                skip = false;
-                continue;
+        let mut chars = input.chars().peekable();
+        while let Some(c) = chars.next() {
+            let unescaped = Scanner::unescape_char(c, *chars.peek().unwrap_or(&'\0'));
+            if unescaped.0 {
+                chars.next();
             }
-            let unescaped = Scanner::unescape_char(
-                input.chars().nth(i).unwrap_or('\0'),
-                input.chars().nth(i+1).unwrap_or('\0'));
-
-            skip = unescaped.0;
-            result = format!("{}{}", result, unescaped.1);
+            result.push(unescaped.1);
         }
 
         return result;
     }
 
+    /// a raw string that starts with a line break is trimmed:
+    /// the first line break, the indentation of the closing line
+    /// and the indentation every line has in common are removed.
+    /// single line raw strings are kept as they are
+    fn trim_indent(text: &str) -> String {
+        let first_break = match text.find('\n') {
+            Some(i) if text[..i].trim().is_empty() => i,
+            _ => return text.to_string()
+        };
+
+        let mut lines: Vec<&str> = text[first_break+1..].split('\n').collect();
+        if let Some(last) = lines.last_mut() {
+            if last.trim().is_empty() {
+                *last = "";
+            }
+        }
+
+        let indent = lines.iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.len() - line.trim_start().len())
+            .min()
+            .unwrap_or(0);
+
+        lines.iter()
+            .map(|line| line.get(indent..).unwrap_or_else(|| line.trim_start()))
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
     fn str_to_real(s: &str) -> Option<ObjReal> {
         match s.parse::<ObjReal>() {
             Ok(n) => return Some(n),
@@ -488,6 +603,13 @@ mod tests {
         assert_eq!(unescaped, "Hello \"World\"\nTHis.\tIs\nAn\nEscaped\rString!\\");
     }
 
+    #[test]
+    fn it_should_trim_indent() {
+        assert_eq!(Scanner::trim_indent("\n    lda #1\n      sta $200\n\n    rts\n  "), "lda #1\n  sta $200\n\nrts\n");
+        assert_eq!(Scanner::trim_indent("  \n\tinx"), "inx");
+        assert_eq!(Scanner::trim_indent(" lda \"a\" "), " lda \"a\" ");
+    }
+
     #[test]
     fn it_should_detect_digits() {
         for c in 'a'..='z' {
@@ -542,43 +664,31 @@ mod tests {
                         "123",
                         1,
                         0,
-                        ""),
+                        "").with_span(Span::new(1, 1, 1, 4)),
                     Token::new(
                         TokenType::Number,
                         Object::Number(456),
                         "456",
                         1,
                         4,
-                        ""),
+                        "").with_span(Span::new(1, 5, 1, 8)),
                     Token::new(
                         TokenType::EndOfFile,
                         Object::Nil,
                         "",
                         1,
                         7,
-                        "")]);
+                        "").with_span(Span::new(1, 8, 1, 8))]);
     }
 
     #[test]
-    fn it_should_scan_decimal_numbers_paren() {
-        let mut scanner = Scanner::new("(123)", "");
+    fn it_should_not_group_numbers_with_parens() {
+        let mut scanner = Scanner::new("(123) 1", "");
 
-        let tokens = scanner.scan().unwrap();
+        let errors = scanner.scan().unwrap_err().errors;
 
-        assert_eq!(tokens, vec![Token::new(
-                        TokenType::Number,
-                        Object::Number(123),
-                        "123",
-                        1,
-                        1,
-                        ""),
-                    Token::new(
-                        TokenType::EndOfFile,
-                        Object::Nil,
-                        "",
-                        1,
-                        5,
-                        "")]);
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec!["type: ParenGrouping; lexeme: (123)".to_string()]);
     }
 
 
@@ -594,14 +704,14 @@ mod tests {
                         "3.1415",
                         1,
                         0,
-                        ""),
+                        "").with_span(Span::new(1, 1, 1, 7)),
                     Token::new(
                         TokenType::EndOfFile,
                         Object::Nil,
                         "",
                         1,
                         6,
-                        "")]);
+                        "").with_span(Span::new(1, 7, 1, 7))]);
     }
 
     #[test]
@@ -616,14 +726,14 @@ mod tests {
                         "0xa123e",
                         1,
                         0,
-                        ""),
+                        "").with_span(Span::new(1, 1, 1, 8)),
                     Token::new(
                         TokenType::EndOfFile,
                         Object::Nil,
                         "",
                         1,
                         7,
-                        "")]);
+                        "").with_span(Span::new(1, 8, 1, 8))]);
     }
 
     #[test]
@@ -638,14 +748,93 @@ mod tests {
                     "0b101",
                     1,
                     0,
-                    ""),
+                    "").with_span(Span::new(1, 1, 1, 6)),
                     Token::new(
                         TokenType::EndOfFile,
                         Object::Nil,
                         "",
                         1,
                         5,
-                        "")]);
+                        "").with_span(Span::new(1, 6, 1, 6))]);
+    }
+
+    fn numbers(source: &str) -> Vec<Object> {
+        let mut scanner = Scanner::new(source, "");
+        scanner.scan().unwrap().into_iter()
+            .filter(|t| t.token_type != TokenType::EndOfFile)
+            .map(|t| t.literal)
+            .collect()
+    }
+
+    #[test]
+    fn it_should_scan_6502_numbers() {
+        assert_eq!(numbers("$FF %1010 $c0_00 %1111_0000"), vec![
+            Object::Number(0xFF),
+            Object::Number(0b1010),
+            Object::Number(0xc000),
+            Object::Number(0b11110000)]);
+    }
+
+    #[test]
+    fn it_should_scan_octal_numbers() {
+        assert_eq!(numbers("0o17 1_000"), vec![Object::Number(0o17), Object::Number(1000)]);
+    }
+
+    #[test]
+    fn it_should_scan_signed_numbers() {
+        assert_eq!(numbers("-5 -$80 -0b1 -1.5 - -x %"), vec![
+            Object::Number(-5),
+            Object::Number(-0x80),
+            Object::Number(-1),
+            Object::Real(-1.5),
+            Object::Word("-".into()),
+            Object::Word("-x".into()),
+            Object::Word("%".into())]);
+    }
+
+    #[test]
+    fn it_should_change_radix() {
+        assert_eq!(numbers("10 :hex 10 0FF -1f $11 %11 :decimal 10"), vec![
+            Object::Number(10),
+            Object::Number(0x10),
+            Object::Number(0xFF),
+            Object::Number(-0x1f),
+            Object::Number(0x11),
+            Object::Number(0b11),
+            Object::Number(10)]);
+    }
+
+    #[test]
+    fn it_should_scan_hex_letters_in_hex_mode() {
+        assert_eq!(numbers("ff :hex ff -ff c0_00 dup - :decimal ff"), vec![
+            Object::Word("ff".into()),
+            Object::Number(0xff),
+            Object::Number(-0xff),
+            Object::Number(0xc000),
+            Object::Word("dup".into()),
+            Object::Word("-".into()),
+            Object::Word("ff".into())]);
+    }
+
+    #[test]
+    fn it_should_not_use_the_binary_prefix_in_hex_mode() {
+        assert_eq!(numbers("0b1 :hex 0b1 0x10"), vec![
+            Object::Number(0b1),
+            Object::Number(0xb1),
+            Object::Number(0x10)]);
+    }
+
+    #[test]
+    fn it_should_not_reserve_radix_words() {
+        assert_eq!(numbers(": hex 1 ; : decimal 10 ;"), vec![
+            Object::Word(":".into()),
+            Object::Word("hex".into()),
+            Object::Number(1),
+            Object::Word(";".into()),
+            Object::Word(":".into()),
+            Object::Word("decimal".into()),
+            Object::Number(10),
+            Object::Word(";".into())]);
     }
 
     #[test]
@@ -660,21 +849,21 @@ mod tests {
                     "atom",
                     1,
                     0,
-                    ""),
+                    "").with_span(Span::new(1, 1, 1, 5)),
                     Token::new(
                         TokenType::If,
                         Object::Word("if".into()),
                         "if",
                         1,
                         5,
-                        ""),
+                        "").with_span(Span::new(1, 6, 1, 8)),
                     Token::new(
                         TokenType::EndOfFile,
                         Object::Nil,
                         "",
                         1,
                         7,
-                        "")]);
+                        "").with_span(Span::new(1, 8, 1, 8))]);
     }
 
     #[test]
@@ -689,19 +878,48 @@ mod tests {
                     "atom",
                     2,
                     10,
-                    ""),
+                    "").with_span(Span::new(2, 1, 2, 5)),
                     Token::new(
                         TokenType::EndOfFile,
                         Object::Nil,
                         "",
                         2,
                         14,
-                        "")]);
+                        "").with_span(Span::new(2, 5, 2, 5))]);
     }
 
     #[test]
-    fn it_not_should_ignore_parens() {
-        let mut scanner = Scanner::new("(\natom)", "");
+    fn it_should_scan_nested_comments() {
+        let mut scanner = Scanner::new("( a ( b\n) -- c ) atom", "");
+
+        let tokens = scanner.scan().unwrap();
+
+        assert_eq!(tokens, vec![Token::new(
+                    TokenType::Comment,
+                    Object::Str(" a ( b\n) -- c ".into()),
+                    "( a ( b\n) -- c )",
+                    1,
+                    0,
+                    "").with_span(Span::new(1, 1, 2, 9)),
+                    Token::new(
+                        TokenType::Word,
+                        Object::Word("atom".into()),
+                        "atom",
+                        2,
+                        17,
+                        "").with_span(Span::new(2, 10, 2, 14)),
+                    Token::new(
+                        TokenType::EndOfFile,
+                        Object::Nil,
+                        "",
+                        2,
+                        21,
+                        "").with_span(Span::new(2, 14, 2, 14))]);
+    }
+
+    #[test]
+    fn it_not_should_scan_line_comments() {
+        let mut scanner = Scanner::new("\\ comment ( \natom", "");
 
         let tokens = scanner.scan().unwrap();
 
@@ -710,15 +928,15 @@ mod tests {
                     Object::Word("atom".into()),
                     "atom",
                     2,
-                    2,
-                    ""),
+                    13,
+                    "").with_span(Span::new(2, 1, 2, 5)),
                     Token::new(
                         TokenType::EndOfFile,
                         Object::Nil,
                         "",
                         2,
-                        7,
-                        "")]);
+                        17,
+                        "").with_span(Span::new(2, 5, 2, 5))]);
     }
 
     #[test]
@@ -733,14 +951,38 @@ mod tests {
                     "\"Hello World!\"",
                     1,
                     0,
-                    ""),
+                    "").with_span(Span::new(1, 1, 1, 15)),
                     Token::new(
                         TokenType::EndOfFile,
                         Object::Nil,
                         "",
                         1,
                         14,
-                        "")]);
+                        "").with_span(Span::new(1, 15, 1, 15))]);
+    }
+
+    #[test]
+    fn it_should_span_multiple_lines() {
+        let mut scanner = Scanner::new("\"a\nbc\" x", "");
+
+        let tokens = scanner.scan().unwrap();
+
+        assert_eq!(tokens[0].line, 1);
+        assert_eq!(tokens[0].span, Span::new(1, 1, 2, 4));
+        assert_eq!(tokens[1].span, Span::new(2, 5, 2, 6));
+    }
+
+    #[test]
+    fn it_should_scan_raw_strings() {
+        let mut scanner = Scanner::new(":asm {{\n  lda \"\\n\"\n  }} {{{ }} }}} x", "");
+
+        let tokens = scanner.scan().unwrap();
+
+        assert_eq!(tokens[1].literal, Object::Str("lda \"\\n\"\n".into()));
+        assert_eq!(tokens[1].span, Span::new(1, 6, 3, 5));
+        assert_eq!(tokens[2].literal, Object::Str(" }} ".into()));
+        assert_eq!(tokens[3].lexeme, "x");
+        assert_eq!(tokens[3].line, 3);
     }
 
     #[test]
@@ -755,14 +997,14 @@ mod tests {
                     "'A'",
                     1,
                     0,
-                    ""),
+                    "").with_span(Span::new(1, 1, 1, 4)),
                     Token::new(
                         TokenType::EndOfFile,
                         Object::Nil,
                         "",
                         1,
                         3,
-                        "")]);
+                        "").with_span(Span::new(1, 4, 1, 4))]);
     }
 
     #[test]
@@ -777,14 +1019,14 @@ mod tests {
                     "' ",
                     1,
                     0,
-                    ""),
+                    "").with_span(Span::new(1, 1, 1, 3)),
                     Token::new(
                         TokenType::EndOfFile,
                         Object::Nil,
                         "",
                         1,
                         2,
-                        "")]);
+                        "").with_span(Span::new(1, 3, 1, 3))]);
     }
 
     // failure tests
@@ -810,6 +1052,20 @@ mod tests {
         assert_eq!(errors_id, vec!["type: NumberParseError; lexeme: 0xag123e".to_string()]);
     }
 
+    #[test]
+    fn it_should_not_scan_invalid_prefixed_numbers() {
+        let mut scanner = Scanner::new("$1g %12 0o8 :hex 1g", "");
+
+        let errors = scanner.scan().unwrap_err().errors;
+
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec![
+            "type: NumberParseError; lexeme: $1g".to_string(),
+            "type: NumberParseError; lexeme: %12".to_string(),
+            "type: NumberParseError; lexeme: 0o8".to_string(),
+            "type: NumberParseError; lexeme: 1g".to_string()]);
+    }
+
     #[test]
     fn it_should_not_scan_invalid_bin_numbers() {
         let mut scanner = Scanner::new("0b102", "");
@@ -823,7 +1079,7 @@ mod tests {
 
     #[test]
     fn it_should_not_scan_invalid_tokens() {
-        let mut scanner = Scanner::new("[", "");
+        let mut scanner = Scanner::new(",", "");
 
         let errors = scanner.scan().unwrap_err().errors;
 
@@ -843,6 +1099,27 @@ mod tests {
         assert_eq!(errors_id, vec!["type: UnterminatedString; lexeme: ".to_string()]);
     }
 
+    #[test]
+    fn it_should_not_scan_unterminated_comments() {
+        let mut scanner = Scanner::new("( a ( b ) -- ", "");
+
+        let errors = scanner.scan().unwrap_err().errors;
+
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec!["type: UnterminatedComment; lexeme: ".to_string()]);
+    }
+
+    #[test]
+    fn it_should_not_scan_unterminated_raw_strings() {
+        let mut scanner = Scanner::new("\n{{{ a }}", "");
+
+        let errors = scanner.scan().unwrap_err().errors;
+
+        assert_eq!(errors[0].downcast_ref::<ExecError>().unwrap().token.line, 2);
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec!["type: UnterminatedString; lexeme: ".to_string()]);
+    }
+
     #[test]
     fn it_should_not_scan_unterminated_char() {
         let mut scanner = Scanner::new("\'A1", "");
@@ -853,4 +1130,58 @@ mod tests {
         let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
         assert_eq!(errors_id, vec!["type: UnterminatedString; lexeme: ".to_string()]);
     }
+
+    #[test]
+    fn it_should_scan_unicode_strings() {
+        let mut scanner = Scanner::new("\"Grüße\\n\" 'ä' {{ ✓ }}", "");
+        let tokens = scanner.scan().unwrap();
+
+        let literals: Vec<Object> = tokens.iter().map(|token| token.literal.clone()).collect();
+        assert_eq!(literals, vec![
+            Object::Str("Grüße\n".into()),
+            Object::Number('ä' as ObjNumber),
+            Object::Str(" ✓ ".into()),
+            Object::Nil]);
+        assert_eq!(tokens[1].span, Span::new(1, 11, 1, 14));
+    }
+
+    #[test]
+    fn it_should_scan_unicode_comments() {
+        let mut scanner = Scanner::new("( größe -- ö ) # äöü\nword", "");
+        let tokens = scanner.scan().unwrap();
+
+        assert_eq!(tokens[0].literal, Object::Str(" größe -- ö ".into()));
+        assert_eq!(tokens[1].lexeme, "word");
+        assert_eq!(tokens[1].span, Span::new(2, 1, 2, 5));
+    }
+
+    #[test]
+    fn it_should_scan_unicode_words() {
+        let mut scanner = Scanner::new("größe→ 日本 ü", "");
+        let errors = scanner.scan().unwrap_err().errors;
+
+        // → is not a letter
+        let errors_id: Vec<String> = errors.iter().map(|x| format!("{:?}", x)).collect();
+        assert_eq!(errors_id, vec!["type: InvalidToken; lexeme: ".to_string()]);
+
+        let mut scanner = Scanner::new("größe 日本 ü", "");
+        let tokens = scanner.scan().unwrap();
+
+        let lexemes: Vec<&str> = tokens.iter().map(|token| token.lexeme.as_ref()).collect();
+        assert_eq!(lexemes, vec!["größe", "日本", "ü", ""]);
+        assert_eq!(tokens[1].span, Span::new(1, 7, 1, 9));
+        assert_eq!(tokens[2].start, 15);
+    }
+
+    #[test]
+    fn it_should_scan_replacement_characters() {
+        // invalid bytes are rejected when the file is read
+        let mut scanner = Scanner::new("( \u{FFFD} ) \"a\u{FFFD}\" # \u{FFFD}\nword", "");
+        let tokens = scanner.scan().unwrap();
+
+        assert_eq!(tokens[0].literal, Object::Str(" \u{FFFD} ".into()));
+        assert_eq!(tokens[1].literal, Object::Str("a\u{FFFD}".into()));
+        assert_eq!(tokens[2].lexeme, "word");
+    }
+
 }
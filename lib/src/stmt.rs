@@ -2,13 +2,23 @@ use super::object::*;
 use super::token::*;
 use super::error::*;
 use super::expr::*;
+use super::effect::StackEffect;
 use std::str;
+use std::rc::Rc;
 
 /// a statement instruction the compiler to
 /// perform an action and returns the resulting code
 #[derive(Clone)]
 pub struct Compiled {
-    pub data: Vec<u8>
+    pub data: Vec<u8>,
+    // the fully qualified word this code defines
+    pub word: Option<String>,
+    // fully qualified words that are called or ticked by this code
+    pub refs: Vec<String>,
+    // entry points are always emitted
+    pub entry: bool,
+    // length of the label emitted by compile at the start of data
+    pub label: usize
 }
 
 impl Compiled {
@@ -27,13 +37,25 @@ impl Compiled {
     pub fn flatten_bytes(data: &mut Vec<Compiled>) -> Compiled {
         let mut result = Compiled::new(vec![]);
         for d in data {
-            result.data.append(&mut d.data);
+            result.append(d);
         }
         return result;
     }
 
     pub fn new(data: Vec<u8>) -> Self {
-        Self {data}
+        Self {
+            data,
+            word: None,
+            refs: vec![],
+            entry: false,
+            label: 0
+        }
+    }
+
+    /// moves other's code and references to the end of self
+    pub fn append(&mut self, other: &mut Compiled) {
+        self.data.append(&mut other.data);
+        self.refs.append(&mut other.refs);
     }
 }
 
@@ -42,13 +64,23 @@ pub enum Stmt {
     Expr(ExprStmt),
     Block(BlockStmt),
     Define(DefineStmt),
+    Let(LetStmt),
+    Forward(ForwardStmt),
+    Cond(CondStmt),
+    Expand(ExpandStmt),
     If(IfStmt),
     Loop(LoopStmt),
+    While(WhileStmt),
+    Case(CaseStmt),
+    Do(DoStmt),
+    Index(IndexStmt),
+    Exit(ExitStmt),
     Import(ImportStmt),
     Use(UseStmt),
     Asm(AsmStmt),
     Mod(ModStmt),
-    Tick(TickStmt)
+    Tick(TickStmt),
+    Peephole(PeepholeStmt)
 }
 
 impl StmtNode for Stmt {
@@ -57,13 +89,73 @@ impl StmtNode for Stmt {
             Self::Expr(expr) => expr.accept(visitor),
             Self::Block(block) => block.accept(visitor),
             Self::Define(define) => define.accept(visitor),
+            Self::Let(letstmt) => letstmt.accept(visitor),
+            Self::Forward(stmt) => stmt.accept(visitor),
+            Self::Cond(stmt) => stmt.accept(visitor),
+            Self::Expand(stmt) => stmt.accept(visitor),
             Self::If(ifstmt) => ifstmt.accept(visitor),
             Self::Loop(loopstmt) => loopstmt.accept(visitor),
+            Self::While(whilestmt) => whilestmt.accept(visitor),
+            Self::Case(casestmt) => casestmt.accept(visitor),
+            Self::Do(dostmt) => dostmt.accept(visitor),
+            Self::Index(stmt) => stmt.accept(visitor),
+            Self::Exit(stmt) => stmt.accept(visitor),
             Self::Import(stmt) => stmt.accept(visitor),
             Self::Mod(modstmt) => modstmt.accept(visitor),
             Self::Asm(asmstmt) => asmstmt.accept(visitor),
             Self::Tick(tickstmt) => tickstmt.accept(visitor),
-            Self::Use(stmt) => stmt.accept(visitor)
+            Self::Use(stmt) => stmt.accept(visitor),
+            Self::Peephole(stmt) => stmt.accept(visitor)
+        }
+    }
+
+    fn token(&self) -> Token {
+        match self {
+            Self::Expr(expr) => expr.token(),
+            Self::Block(block) => block.token(),
+            Self::Define(define) => define.token(),
+            Self::Let(letstmt) => letstmt.token(),
+            Self::Forward(stmt) => stmt.token(),
+            Self::Cond(stmt) => stmt.token(),
+            Self::Expand(stmt) => stmt.token(),
+            Self::If(ifstmt) => ifstmt.token(),
+            Self::Loop(loopstmt) => loopstmt.token(),
+            Self::While(whilestmt) => whilestmt.token(),
+            Self::Case(casestmt) => casestmt.token(),
+            Self::Do(dostmt) => dostmt.token(),
+            Self::Index(stmt) => stmt.token(),
+            Self::Exit(stmt) => stmt.token(),
+            Self::Import(stmt) => stmt.token(),
+            Self::Mod(modstmt) => modstmt.token(),
+            Self::Asm(asmstmt) => asmstmt.token(),
+            Self::Tick(tickstmt) => tickstmt.token(),
+            Self::Use(stmt) => stmt.token(),
+            Self::Peephole(stmt) => stmt.token()
+        }
+    }
+
+    fn span(&self) -> Span {
+        match self {
+            Self::Expr(expr) => expr.span(),
+            Self::Block(block) => block.span(),
+            Self::Define(define) => define.span(),
+            Self::Let(letstmt) => letstmt.span(),
+            Self::Forward(stmt) => stmt.span(),
+            Self::Cond(stmt) => stmt.span(),
+            Self::Expand(stmt) => stmt.span(),
+            Self::If(ifstmt) => ifstmt.span(),
+            Self::Loop(loopstmt) => loopstmt.span(),
+            Self::While(whilestmt) => whilestmt.span(),
+            Self::Case(casestmt) => casestmt.span(),
+            Self::Do(dostmt) => dostmt.span(),
+            Self::Index(stmt) => stmt.span(),
+            Self::Exit(stmt) => stmt.span(),
+            Self::Import(stmt) => stmt.span(),
+            Self::Mod(modstmt) => modstmt.span(),
+            Self::Asm(asmstmt) => asmstmt.span(),
+            Self::Tick(tickstmt) => tickstmt.span(),
+            Self::Use(stmt) => stmt.span(),
+            Self::Peephole(stmt) => stmt.span()
         }
     }
 }
@@ -74,19 +166,34 @@ pub trait StmtNode {
     fn token(&self) -> Token {
         Token::new(TokenType::Invalid, Object::Nil, "", 0, 0, "")
     }
+
+    /// the source covered by the whole statement
+    fn span(&self) -> Span {
+        self.token().span
+    }
 }
 
 pub trait StmtVisitor {
     fn visit_expr(&mut self, stmt: &mut ExprStmt) -> BoxResult<Compiled>;
     fn visit_block(&mut self, stmt: &mut BlockStmt) -> BoxResult<Compiled>;
     fn visit_define(&mut self, stmt: &mut DefineStmt) -> BoxResult<Compiled>;
+    fn visit_let(&mut self, stmt: &mut LetStmt) -> BoxResult<Compiled>;
+    fn visit_forward(&mut self, stmt: &mut ForwardStmt) -> BoxResult<Compiled>;
+    fn visit_cond(&mut self, stmt: &mut CondStmt) -> BoxResult<Compiled>;
+    fn visit_expand(&mut self, stmt: &mut ExpandStmt) -> BoxResult<Compiled>;
     fn visit_if(&mut self, stmt: &mut IfStmt) -> BoxResult<Compiled>;
     fn visit_loop(&mut self, stmt: &mut LoopStmt) -> BoxResult<Compiled>;
+    fn visit_while(&mut self, stmt: &mut WhileStmt) -> BoxResult<Compiled>;
+    fn visit_case(&mut self, stmt: &mut CaseStmt) -> BoxResult<Compiled>;
+    fn visit_do(&mut self, stmt: &mut DoStmt) -> BoxResult<Compiled>;
+    fn visit_index(&mut self, stmt: &mut IndexStmt) -> BoxResult<Compiled>;
+    fn visit_exit(&mut self, stmt: &mut ExitStmt) -> BoxResult<Compiled>;
     fn visit_impoprt(&mut self, stmt: &mut ImportStmt) -> BoxResult<Compiled>;
     fn visit_mod(&mut self, stmt: &mut ModStmt) -> BoxResult<Compiled>;
     fn visit_asm(&mut self, stmt: &mut AsmStmt) -> BoxResult<Compiled>;
     fn visit_tick(&mut self, stmt: &mut TickStmt) -> BoxResult<Compiled>;
     fn visit_use(&mut self, stmt: &mut UseStmt) -> BoxResult<Compiled>;
+    fn visit_peephole(&mut self, stmt: &mut PeepholeStmt) -> BoxResult<Compiled>;
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -110,17 +217,23 @@ impl StmtNode for ExprStmt {
     fn token(&self) -> Token {
         self.expr.token()
     }
+
+    fn span(&self) -> Span {
+        self.expr.span()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct BlockStmt {
     pub token: Token,
-    pub body: Vec<Stmt>
+    pub body: Vec<Stmt>,
+    pub span: Span
 }
 
 impl BlockStmt {
     pub fn new(body: Vec<Stmt>, token: Token) -> Self {
         Self {
+            span: token.span,
             token,
             body
         }
@@ -135,13 +248,21 @@ impl StmtNode for BlockStmt {
     fn token(&self) -> Token {
         self.token.clone()
     }
+
+    fn span(&self) -> Span {
+        self.span
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct DefineStmt {
     pub name: Token,
     pub body: Box<Stmt>,
-    pub mode: DefineMode
+    pub mode: DefineMode,
+    pub attributes: Vec<Token>,
+    // declared stack effect
+    pub effect: Option<StackEffect>,
+    pub span: Span
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -153,12 +274,23 @@ pub enum DefineMode {
 
 impl DefineStmt {
     pub fn new(name: Token, body: Box<Stmt>, mode: DefineMode) -> Self {
+        Self::with(name, body, mode, vec![])
+    }
+
+    pub fn with(name: Token, body: Box<Stmt>, mode: DefineMode, attributes: Vec<Token>) -> Self {
         Self {
+            span: name.span,
             name,
             body,
-            mode
+            mode,
+            attributes,
+            effect: None
         }
     }
+
+    pub fn has_attribute(&self, attribute: TokenType) -> bool {
+        self.attributes.iter().any(|a| a.token_type == attribute)
+    }
 }
 
 impl StmtNode for DefineStmt {
@@ -169,18 +301,24 @@ impl StmtNode for DefineStmt {
     fn token(&self) -> Token {
         self.name.clone()
     }
+
+    fn span(&self) -> Span {
+        self.span
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct IfStmt {
     pub token: Token,
     pub then_block: Box<Stmt>,
-    pub else_block: Option<Box<Stmt>>
+    pub else_block: Option<Box<Stmt>>,
+    pub span: Span
 }
 
 impl IfStmt {
     pub fn new(then_block: Box<Stmt>, else_block: Option<Box<Stmt>>, token: Token) -> Self {
         Self {
+            span: token.span,
             token,
             then_block,
             else_block
@@ -197,19 +335,24 @@ impl StmtNode for IfStmt {
         self.token.clone()
     }
 
+    fn span(&self) -> Span {
+        self.span
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct LoopStmt {
     pub token: Token,
     pub block: Box<Stmt>,
+    pub span: Span
 }
 
 impl LoopStmt {
     pub fn new(block: Box<Stmt>, token: Token) -> Self {
         Self {
+            span: token.span,
             token,
-            block,
+            block
         }
     }
 }
@@ -223,17 +366,367 @@ impl StmtNode for LoopStmt {
         self.token.clone()
     }
 
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// :let <name> <width> declares a variable
+#[derive(Debug, Clone, PartialEq)]
+pub struct LetStmt {
+    pub token: Token,
+    pub name: Token,
+    pub width: Token,
+    pub span: Span
+}
+
+impl LetStmt {
+    pub fn new(name: Token, width: Token, token: Token) -> Self {
+        Self {
+            span: token.span.merge(&width.span),
+            token,
+            name,
+            width
+        }
+    }
+
+    /// the name of the width used by the fetch and store words
+    pub fn width_name(&self) -> String {
+        self.width.lexeme.trim_start_matches(':').to_string()
+    }
+
+    /// the size of the variable in bytes
+    pub fn size(&self) -> usize {
+        match self.width.token_type {
+            TokenType::I16 => 2,
+            TokenType::I32 => 4,
+            TokenType::I64 => 8,
+            _ => 1
+        }
+    }
+}
+
+impl StmtNode for LetStmt {
+    fn accept(&mut self, visitor: &mut dyn StmtVisitor) -> BoxResult<Compiled> {
+        return visitor.visit_let(self);
+    }
+
+    fn token(&self) -> Token {
+        self.token.clone()
+    }
+
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// :forward <name> ; declares a word that is defined later
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardStmt {
+    pub token: Token,
+    pub name: Token,
+    pub effect: Option<StackEffect>,
+    pub span: Span
+}
+
+impl ForwardStmt {
+    pub fn new(name: Token, token: Token) -> Self {
+        Self {
+            span: token.span.merge(&name.span),
+            token,
+            name,
+            effect: None
+        }
+    }
+}
+
+impl StmtNode for ForwardStmt {
+    fn accept(&mut self, visitor: &mut dyn StmtVisitor) -> BoxResult<Compiled> {
+        return visitor.visit_forward(self);
+    }
+
+    fn token(&self) -> Token {
+        self.token.clone()
+    }
+
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    // :if <const-expr> ;
+    Expr(Box<Stmt>),
+    // :ifdef <name>
+    Defined(Token)
+}
+
+/// conditional compilation.
+/// :if <const-expr> ; <stmts> :else <stmts> :endif
+#[derive(Debug, Clone, PartialEq)]
+pub struct CondStmt {
+    pub token: Token,
+    pub condition: Condition,
+    pub then_branch: Vec<Stmt>,
+    pub else_branch: Vec<Stmt>,
+    pub span: Span
+}
+
+impl CondStmt {
+    pub fn new(condition: Condition, then_branch: Vec<Stmt>, else_branch: Vec<Stmt>, token: Token) -> Self {
+        Self {
+            span: token.span,
+            token,
+            condition,
+            then_branch,
+            else_branch
+        }
+    }
+}
+
+impl StmtNode for CondStmt {
+    fn accept(&mut self, visitor: &mut dyn StmtVisitor) -> BoxResult<Compiled> {
+        return visitor.visit_cond(self);
+    }
+
+    fn token(&self) -> Token {
+        self.token.clone()
+    }
+
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// the body of a macro at its use site
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpandStmt {
+    pub token: Token,
+    pub body: Vec<Stmt>,
+    pub span: Span
+}
+
+impl ExpandStmt {
+    pub fn new(body: Vec<Stmt>, token: Token) -> Self {
+        Self {
+            span: token.span,
+            token,
+            body
+        }
+    }
+}
+
+impl StmtNode for ExpandStmt {
+    fn accept(&mut self, visitor: &mut dyn StmtVisitor) -> BoxResult<Compiled> {
+        return visitor.visit_expand(self);
+    }
+
+    fn token(&self) -> Token {
+        self.token.clone()
+    }
+
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// begin <condition> while <block> repeat
+#[derive(Debug, Clone, PartialEq)]
+pub struct WhileStmt {
+    pub token: Token,
+    pub condition: Box<Stmt>,
+    pub block: Box<Stmt>,
+    pub span: Span
+}
+
+impl WhileStmt {
+    pub fn new(condition: Box<Stmt>, block: Box<Stmt>, token: Token) -> Self {
+        Self {
+            span: token.span,
+            token,
+            condition,
+            block
+        }
+    }
+}
+
+impl StmtNode for WhileStmt {
+    fn accept(&mut self, visitor: &mut dyn StmtVisitor) -> BoxResult<Compiled> {
+        return visitor.visit_while(self);
+    }
+
+    fn token(&self) -> Token {
+        self.token.clone()
+    }
+
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// <value> of <block> endof
+#[derive(Debug, Clone, PartialEq)]
+pub struct OfBranch {
+    pub token: Token,
+    pub value: Box<Stmt>,
+    pub block: Box<Stmt>
+}
+
+impl OfBranch {
+    pub fn new(value: Box<Stmt>, block: Box<Stmt>, token: Token) -> Self {
+        Self {
+            token,
+            value,
+            block
+        }
+    }
+}
+
+/// <selector> case <branches> <default> endcase
+/// the default block runs with the selector on the stack
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaseStmt {
+    pub token: Token,
+    pub branches: Vec<OfBranch>,
+    pub default: Box<Stmt>,
+    pub span: Span
+}
+
+impl CaseStmt {
+    pub fn new(branches: Vec<OfBranch>, default: Box<Stmt>, token: Token) -> Self {
+        Self {
+            span: token.span,
+            token,
+            branches,
+            default
+        }
+    }
+}
+
+impl StmtNode for CaseStmt {
+    fn accept(&mut self, visitor: &mut dyn StmtVisitor) -> BoxResult<Compiled> {
+        return visitor.visit_case(self);
+    }
+
+    fn token(&self) -> Token {
+        self.token.clone()
+    }
+
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// limit start do ... loop
+/// +loop takes the step from the stack
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoStmt {
+    pub token: Token,
+    pub block: Box<Stmt>,
+    pub plus: bool,
+    pub span: Span
+}
+
+impl DoStmt {
+    pub fn new(block: Box<Stmt>, plus: bool, token: Token) -> Self {
+        Self {
+            span: token.span,
+            token,
+            block,
+            plus
+        }
+    }
+}
+
+impl StmtNode for DoStmt {
+    fn accept(&mut self, visitor: &mut dyn StmtVisitor) -> BoxResult<Compiled> {
+        return visitor.visit_do(self);
+    }
+
+    fn token(&self) -> Token {
+        self.token.clone()
+    }
+
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// i pushes the index of the innermost do loop
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexStmt {
+    pub token: Token,
+    pub span: Span
+}
+
+impl IndexStmt {
+    pub fn new(token: Token) -> Self {
+        Self {
+            span: token.span,
+            token
+        }
+    }
+}
+
+impl StmtNode for IndexStmt {
+    fn accept(&mut self, visitor: &mut dyn StmtVisitor) -> BoxResult<Compiled> {
+        return visitor.visit_index(self);
+    }
+
+    fn token(&self) -> Token {
+        self.token.clone()
+    }
+
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// exit returns from the word early
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExitStmt {
+    pub token: Token,
+    // the word that is left
+    pub word: Token,
+    pub span: Span
+}
+
+impl ExitStmt {
+    pub fn new(word: Token, token: Token) -> Self {
+        Self {
+            span: token.span,
+            token,
+            word
+        }
+    }
+}
+
+impl StmtNode for ExitStmt {
+    fn accept(&mut self, visitor: &mut dyn StmtVisitor) -> BoxResult<Compiled> {
+        return visitor.visit_exit(self);
+    }
+
+    fn token(&self) -> Token {
+        self.token.clone()
+    }
+
+    fn span(&self) -> Span {
+        self.span
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct AsmStmt {
     pub token: Token,
     pub code: Object,
+    pub span: Span
 }
 
 impl AsmStmt {
     pub fn new(code: Object, token: Token) -> Self {
         Self {
+            span: token.span,
             token,
             code
         }
@@ -248,19 +741,36 @@ impl StmtNode for AsmStmt {
     fn token(&self) -> Token {
         self.token.clone()
     }
+
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// an imported file that is parsed together with the file importing it
+#[derive(Debug, PartialEq)]
+pub struct ParsedModule {
+    pub source: String,
+    pub stmts: Vec<Stmt>
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ImportStmt {
     pub token: Token,
     pub path: Object,
+    // None if the file could not be read or parsed.
+    // the compiler reports the error if the import is compiled
+    pub module: Option<Rc<ParsedModule>>,
+    pub span: Span
 }
 
 impl ImportStmt {
     pub fn new(path: Object, token: Token) -> Self {
         Self {
+            span: token.span,
             token,
-            path
+            path,
+            module: None
         }
     }
 }
@@ -273,16 +783,22 @@ impl StmtNode for ImportStmt {
     fn token(&self) -> Token {
         self.token.clone()
     }
+
+    fn span(&self) -> Span {
+        self.span
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ModStmt {
     pub name: Token,
+    pub span: Span
 }
 
 impl ModStmt {
     pub fn new(name: Token) -> Self {
         Self {
+            span: name.span,
             name
         }
     }
@@ -296,17 +812,23 @@ impl StmtNode for ModStmt {
     fn token(&self) -> Token {
         self.name.clone()
     }
+
+    fn span(&self) -> Span {
+        self.span
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct TickStmt {
     pub token: Token,
-    pub word: Expr
+    pub word: Expr,
+    pub span: Span
 }
 
 impl TickStmt {
     pub fn new(word: Expr, token: Token) -> Self {
         Self {
+            span: token.span.merge(&word.span()),
             word,
             token
         }
@@ -321,17 +843,23 @@ impl StmtNode for TickStmt {
     fn token(&self) -> Token {
         self.token.clone()
     }
+
+    fn span(&self) -> Span {
+        self.span
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct UseStmt {
     pub module: Token,
-    pub words: Vec<Token>
+    pub words: Vec<Token>,
+    pub span: Span
 }
 
 impl UseStmt {
     pub fn new(module: Token, words: Vec<Token>) -> Self {
         Self {
+            span: module.span,
             module,
             words
         }
@@ -346,5 +874,43 @@ impl StmtNode for UseStmt {
     fn token(&self) -> Token {
         self.module.clone()
     }
+
+    fn span(&self) -> Span {
+        self.span
+    }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeepholeStmt {
+    pub token: Token,
+    pub first: Token,
+    pub second: Token,
+    pub replacement: Vec<Token>,
+    pub span: Span
+}
+
+impl PeepholeStmt {
+    pub fn new(first: Token, second: Token, replacement: Vec<Token>, token: Token) -> Self {
+        Self {
+            span: token.span,
+            token,
+            first,
+            second,
+            replacement
+        }
+    }
+}
+
+impl StmtNode for PeepholeStmt {
+    fn accept(&mut self, visitor: &mut dyn StmtVisitor) -> BoxResult<Compiled> {
+        return visitor.visit_peephole(self);
+    }
+
+    fn token(&self) -> Token {
+        self.token.clone()
+    }
+
+    fn span(&self) -> Span {
+        self.span
+    }
+}
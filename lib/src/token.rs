@@ -15,8 +15,25 @@ pub enum TokenType {
     Until,
     Loop,
     Do,
+    PlusLoop,
+    While,
+    Repeat,
+    Case,
+    Of,
+    EndOf,
+    EndCase,
+    Index,
+    Recurse,
+    Exit,
 
-    Let,
+    Let, // :let <name> <width>
+    Forward, // :forward <name> ;
+
+    // conditional compilation
+    CondIf, // :if <const-expr> ;
+    CondIfDef, // :ifdef <name>
+    CondElse,
+    CondEndIf,
     StartConstDefine,
 
     StartDefine,
@@ -35,17 +52,59 @@ pub enum TokenType {
     Import, // :import "file"
     Mod, // :mod module_name
     Tick, // used to find definition of word
+    Peephole, // :peephole <word> <word> <replacement>... ;
+    Parsing, // :parsing <word> <tokens> <body> ;
+    Macro, // :macro <word> <params>... ; <body> ;
+    StartQuote, // [ <stmts> ] passes statements to a macro
+    EndQuote,
+
+    // attributes following the name of a definition
+    Entry, // :entry word is always emitted
+    NoInline, // :noinline word is never inlined automatically
+    Effect, // :effect <inputs> <outputs> declares the stack effect
+
+    Comment, // ( <inputs> -- <outputs> ) is also a stack effect
 
     EndOfFile,
 }
 
+/// the area of the source a token or statement covers.
+/// lines and columns start at 1, the end column is exclusive
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize, end_line: usize, end_column: usize) -> Self {
+        Self {
+            line,
+            column,
+            end_line,
+            end_column
+        }
+    }
+
+    /// the smallest span containing both spans
+    pub fn merge(&self, other: &Span) -> Span {
+        let start = (self.line, self.column).min((other.line, other.column));
+        let end = (self.end_line, self.end_column).max((other.end_line, other.end_column));
+        Span::new(start.0, start.1, end.0, end.1)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Token {
     pub token_type: TokenType,
     pub literal: Object,
     pub path: String,
     pub line: usize,
+    // byte offset of the first character in the source
     pub start: usize,
+    pub span: Span,
     pub lexeme: String
 }
 
@@ -63,10 +122,17 @@ impl Token {
             path: path.into(),
             lexeme: lexeme.into(),
             start,
+            // only scanned tokens have columns
+            span: Span::new(line, 0, line, 0),
             line
         }
     }
 
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = span;
+        self
+    }
+
     /// returns a word attached to this token
     /// usually only used for keywords
     pub fn word(&self) -> &str {
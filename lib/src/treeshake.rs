@@ -0,0 +1,123 @@
+use super::stmt::Compiled;
+use super::dictionary::Dictionary;
+use std::collections::{HashMap, HashSet};
+
+/// removes every word definition that can not be reached
+/// from top-level code or an entry point.
+/// a word is reachable if it is called or ticked by reachable code
+/// or if its name is used as a label in the assembly of reachable code
+pub struct TreeShaker;
+
+impl TreeShaker {
+    pub fn shake(output: &mut Vec<Compiled>) {
+        // a word may be defined more than once.
+        // asm code refers to words by their label
+        let mut definitions: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut reachable = HashSet::new();
+        let mut work = vec![];
+
+        for (i, fragment) in output.iter().enumerate() {
+            match &fragment.word {
+                Some(word) if !fragment.entry => {
+                    definitions.entry(word.clone()).or_default().push(i);
+                    let label = Dictionary::get_label(word);
+                    if label != *word {
+                        definitions.entry(label).or_default().push(i);
+                    }
+                },
+                _ => {
+                    reachable.insert(i);
+                    work.push(i);
+                }
+            }
+        }
+
+        while let Some(i) = work.pop() {
+            let code = String::from_utf8_lossy(&output[i].data);
+            let labels = code.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'));
+            for r in output[i].refs.iter().map(|r| r.as_str()).chain(labels) {
+                for def in definitions.get(r).unwrap_or(&vec![]) {
+                    if reachable.insert(*def) {
+                        work.push(*def);
+                    }
+                }
+            }
+        }
+
+        let mut i = 0;
+        output.retain(|_| {
+            i += 1;
+            reachable.contains(&(i - 1))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(name: &str, refs: Vec<&str>) -> Compiled {
+        let mut compiled = Compiled::new(name.as_bytes().to_vec());
+        compiled.word = Some(name.into());
+        compiled.refs = refs.iter().map(|r| r.to_string()).collect();
+        compiled
+    }
+
+    fn code(refs: Vec<&str>) -> Compiled {
+        let mut compiled = Compiled::new(b"code".to_vec());
+        compiled.refs = refs.iter().map(|r| r.to_string()).collect();
+        compiled
+    }
+
+    fn names(output: &[Compiled]) -> Vec<String> {
+        output.iter().map(|c| String::from_utf8(c.data.clone()).unwrap()).collect()
+    }
+
+    #[test]
+    fn it_should_remove_unused_words() {
+        let mut output = vec![word("a", vec![]), word("b", vec![]), code(vec!["b"])];
+        TreeShaker::shake(&mut output);
+
+        assert_eq!(names(&output), vec!["b", "code"]);
+    }
+
+    #[test]
+    fn it_should_keep_transitive_calls() {
+        let mut output = vec![
+            word("a", vec![]),
+            word("b", vec!["a"]),
+            word("c", vec!["c"]),
+            code(vec!["b"])];
+        TreeShaker::shake(&mut output);
+
+        assert_eq!(names(&output), vec!["a", "b", "code"]);
+    }
+
+    #[test]
+    fn it_should_keep_entry_points() {
+        let mut entry = word("nmi", vec!["a"]);
+        entry.entry = true;
+        let mut output = vec![word("a", vec![]), word("b", vec![]), entry];
+        TreeShaker::shake(&mut output);
+
+        assert_eq!(names(&output), vec!["a", "nmi"]);
+    }
+
+    #[test]
+    fn it_should_keep_words_used_in_asm() {
+        let jump = Compiled::new(b"    jmp main\n".to_vec());
+        let mut output = vec![word("main", vec![]), word("mainloop", vec![]), jump];
+        TreeShaker::shake(&mut output);
+
+        assert_eq!(names(&output), vec!["main", "    jmp main\n"]);
+    }
+
+    #[test]
+    fn it_should_keep_module_words_used_in_asm() {
+        let jump = Compiled::new(b"    jmp m__mod__main\n".to_vec());
+        let mut output = vec![word("m::main", vec![]), word("m::other", vec![]), jump];
+        TreeShaker::shake(&mut output);
+
+        assert_eq!(names(&output), vec!["m::main", "    jmp m__mod__main\n"]);
+    }
+}